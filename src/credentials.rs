@@ -0,0 +1,246 @@
+//! Registry credentials, kept in the same format as Docker's `config.json`
+//!
+//! `login` writes to `~/.mydocker/config.json`.
+//! Registries not found there are looked up in Docker's own `~/.docker/config.json`, or `$DOCKER_CONFIG/config.json`.
+//! Credential helpers (`credsStore`, `credHelpers`) are not supported.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::{Deserialize, Serialize};
+
+use crate::image_reference::DOCKER_HUB_DOMAIN;
+
+/// Key Docker stores Docker Hub credentials under
+const DOCKER_HUB_CONFIG_KEY: &str = "https://index.docker.io/v1/";
+const DOCKER_HUB_ALIASES: [&str; 4] = [
+    DOCKER_HUB_DOMAIN,
+    "index.docker.io",
+    "registry-1.docker.io",
+    "registry.hub.docker.com",
+];
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+// https://docs.docker.com/engine/reference/commandline/cli/#configuration-files
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ConfigFile {
+    #[serde(default)]
+    auths: BTreeMap<String, AuthEntry>,
+    /// Everything else Docker keeps there, written back untouched
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AuthEntry {
+    /// base64 of `username:password`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ConfigFile {
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        match std::fs::read(path) {
+            Ok(config) => serde_json::from_slice(&config)
+                .with_context(|| format!("malformed config file `{}`", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write aside and rename, readable by the owner only
+    pub fn save(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp_path = path.with_extension("json.tmp");
+        let mut file = std::fs::File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        drop(file);
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, domain: &str) -> anyhow::Result<Option<Credentials>> {
+        let domain = normalize_domain(domain);
+        let Some((key, entry)) = self
+            .auths
+            .iter()
+            .find(|(key, _)| normalize_domain(key) == domain)
+        else {
+            return Ok(None);
+        };
+        let credentials = match (&entry.auth, &entry.username, &entry.password) {
+            (Some(auth), _, _) if !auth.is_empty() => {
+                let auth = String::from_utf8(BASE64.decode(auth.trim())?)?;
+                let (username, password) = auth
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("malformed `auth` of `{key}`"))?;
+                Credentials {
+                    username: username.to_string(),
+                    password: password.to_string(),
+                }
+            }
+            (_, Some(username), Some(password)) => Credentials {
+                username: username.clone(),
+                password: password.clone(),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(credentials))
+    }
+
+    pub fn insert(&mut self, domain: &str, credentials: &Credentials) {
+        self.remove(domain);
+        let auth = format!("{}:{}", credentials.username, credentials.password);
+        self.auths.insert(
+            config_key(domain),
+            AuthEntry {
+                auth: Some(BASE64.encode(auth)),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Returns whether there were credentials for `domain`
+    pub fn remove(&mut self, domain: &str) -> bool {
+        let domain = normalize_domain(domain);
+        let before = self.auths.len();
+        self.auths.retain(|key, _| normalize_domain(key) != domain);
+        self.auths.len() != before
+    }
+}
+
+/// `~/.mydocker/config.json`, where `login` stores credentials
+pub fn config_file_path() -> anyhow::Result<PathBuf> {
+    our_config_file_path().ok_or_else(|| anyhow::anyhow!("`HOME` is not set"))
+}
+
+fn our_config_file_path() -> Option<PathBuf> {
+    Some(home_dir()?.join(".mydocker").join("config.json"))
+}
+
+fn docker_config_file_path() -> Option<PathBuf> {
+    match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => Some(PathBuf::from(dir).join("config.json")),
+        None => Some(home_dir()?.join(".docker").join("config.json")),
+    }
+}
+
+pub(crate) fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(PathBuf::from)
+}
+
+/// Credentials for the registry at `domain`, from our config file or else from Docker's
+///
+/// Without `HOME` there are no config files to look into, hence no credentials.
+/// A config file that cannot be read is skipped with a warning, so that public images can still be pulled.
+pub fn lookup(domain: &str) -> anyhow::Result<Option<Credentials>> {
+    let config_file_paths = [our_config_file_path(), docker_config_file_path()];
+    for path in config_file_paths.into_iter().flatten() {
+        let config = match ConfigFile::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Skipping config file `{}`: {e:#}", path.display());
+                continue;
+            }
+        };
+        if let Some(credentials) = config.get(domain)? {
+            return Ok(Some(credentials));
+        }
+    }
+    Ok(None)
+}
+
+/// `https://registry.example.com:5000/v2/` → `registry.example.com:5000`, with Docker Hub aliases folded into `docker.io`
+pub fn normalize_domain(key: &str) -> String {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+    let domain = key.split('/').next().unwrap_or_default().to_lowercase();
+    match DOCKER_HUB_ALIASES.contains(&domain.as_str()) {
+        true => DOCKER_HUB_DOMAIN.to_string(),
+        false => domain,
+    }
+}
+
+fn config_key(domain: &str) -> String {
+    match normalize_domain(domain).as_str() {
+        DOCKER_HUB_DOMAIN => DOCKER_HUB_CONFIG_KEY.to_string(),
+        domain => domain.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_docker_config() {
+        let config: ConfigFile = serde_json::from_str(
+            r#"{
+                "auths": {
+                    "https://index.docker.io/v1/": { "auth": "YWxpY2U6czNjcjN0" },
+                    "registry.example.com:5000": { "username": "bob", "password": "hunter2" },
+                    "ghcr.io": {}
+                },
+                "credsStore": "desktop"
+            }"#,
+        )
+        .unwrap();
+        let alice = Credentials {
+            username: "alice".to_string(),
+            password: "s3cr3t".to_string(),
+        };
+        assert_eq!(config.get("docker.io").unwrap(), Some(alice.clone()));
+        assert_eq!(
+            config
+                .get("registry.example.com:5000")
+                .unwrap()
+                .unwrap()
+                .username,
+            "bob"
+        );
+        assert_eq!(config.get("ghcr.io").unwrap(), None);
+        assert_eq!(config.get("quay.io").unwrap(), None);
+
+        let mut config = config;
+        assert!(config.remove("index.docker.io"));
+        assert_eq!(config.get("docker.io").unwrap(), None);
+        config.insert("docker.io", &alice);
+        assert_eq!(config.get("registry-1.docker.io").unwrap(), Some(alice));
+        let saved = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            saved["auths"]["https://index.docker.io/v1/"]["auth"],
+            "YWxpY2U6czNjcjN0"
+        );
+        assert_eq!(saved["credsStore"], "desktop");
+    }
+}
@@ -0,0 +1,163 @@
+// https://github.com/opencontainers/image-spec/blob/main/descriptor.md#digests
+
+use sha2::{Digest as _, Sha256, Sha512};
+use tokio::io::AsyncReadExt;
+
+/// Incrementally hashes content and checks it against an expected digest and size
+pub struct DigestVerifier {
+    expected: String,
+    expected_size: Option<usize>,
+    hasher: Hasher,
+    size: usize,
+}
+
+enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl DigestVerifier {
+    pub fn new(expected: &str, expected_size: Option<usize>) -> Result<Self, DigestError> {
+        let (algorithm, _) = expected
+            .split_once(':')
+            .ok_or_else(|| DigestError::Malformed(expected.to_string()))?;
+        let hasher = match algorithm {
+            "sha256" => Hasher::Sha256(Sha256::new()),
+            "sha512" => Hasher::Sha512(Sha512::new()),
+            _ => return Err(DigestError::UnsupportedAlgorithm(algorithm.to_string())),
+        };
+        Ok(Self {
+            expected: expected.to_string(),
+            expected_size,
+            hasher,
+            size: 0,
+        })
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.size += bytes.len();
+        match &mut self.hasher {
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+            Hasher::Sha512(hasher) => hasher.update(bytes),
+        }
+    }
+
+    /// Feed the whole content of `file_path` to the hasher
+    pub async fn update_from_file(
+        &mut self,
+        file_path: impl AsRef<std::path::Path>,
+    ) -> std::io::Result<()> {
+        let mut file = tokio::fs::File::open(file_path).await?;
+        let mut buf = vec![0; 1 << 16];
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            self.update(&buf[..n]);
+        }
+        Ok(())
+    }
+
+    pub fn verify(self) -> Result<(), DigestError> {
+        if let Some(expected_size) = self.expected_size {
+            if expected_size != self.size {
+                return Err(DigestError::SizeMismatch {
+                    expected: expected_size,
+                    actual: self.size,
+                });
+            }
+        }
+        let actual = match self.hasher {
+            Hasher::Sha256(hasher) => format!("sha256:{}", hex::encode(hasher.finalize())),
+            Hasher::Sha512(hasher) => format!("sha512:{}", hex::encode(hasher.finalize())),
+        };
+        if !actual.eq_ignore_ascii_case(&self.expected) {
+            return Err(DigestError::Mismatch {
+                expected: self.expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// `sha256:<hex>` digest of `bytes`
+pub fn sha256_digest(bytes: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(bytes)))
+}
+
+pub fn verify_bytes(
+    expected: &str,
+    expected_size: Option<usize>,
+    bytes: &[u8],
+) -> Result<(), DigestError> {
+    let mut verifier = DigestVerifier::new(expected, expected_size)?;
+    verifier.update(bytes);
+    verifier.verify()
+}
+
+pub async fn verify_file(
+    expected: &str,
+    expected_size: Option<usize>,
+    file_path: impl AsRef<std::path::Path>,
+) -> anyhow::Result<()> {
+    let mut verifier = DigestVerifier::new(expected, expected_size)?;
+    verifier.update_from_file(file_path).await?;
+    verifier.verify()?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DigestError {
+    #[error("malformed digest `{0}`")]
+    Malformed(String),
+    #[error("unsupported digest algorithm `{0}`")]
+    UnsupportedAlgorithm(String),
+    #[error("expected {expected} bytes but got {actual}")]
+    SizeMismatch { expected: usize, actual: usize },
+    #[error("expected digest `{expected}` but got `{actual}`")]
+    Mismatch { expected: String, actual: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO_SHA256: &str =
+        "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_verify_bytes() {
+        verify_bytes(HELLO_SHA256, Some(5), b"hello").unwrap();
+        verify_bytes(HELLO_SHA256, None, b"hello").unwrap();
+        assert_eq!(sha256_digest(b"hello"), HELLO_SHA256);
+    }
+
+    #[test]
+    fn test_reject_truncated() {
+        assert_eq!(
+            verify_bytes(HELLO_SHA256, Some(5), b"hell"),
+            Err(DigestError::SizeMismatch {
+                expected: 5,
+                actual: 4
+            })
+        );
+        assert!(matches!(
+            verify_bytes(HELLO_SHA256, None, b"hell"),
+            Err(DigestError::Mismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_reject_unsupported() {
+        assert!(matches!(
+            verify_bytes("md5:abc", None, b""),
+            Err(DigestError::UnsupportedAlgorithm(_))
+        ));
+        assert!(matches!(
+            verify_bytes("abc", None, b""),
+            Err(DigestError::Malformed(_))
+        ));
+    }
+}
@@ -1,6 +1,8 @@
 use clap::Args;
 
-use crate::{execute_command, pid_file_path, process_alive, read_pid, root_fs_path, write_pid};
+use crate::{
+    execute_command, pid_file_path, process_alive, read_pid, root_fs_path, write_pid, ProcessConfig,
+};
 
 #[derive(Debug, Args)]
 pub struct ExecArgs {
@@ -25,6 +27,11 @@ impl ExecArgs {
 
         // Execute the command
         let root_fs = root_fs_path(&self.container);
-        execute_command(&self.command, &self.command_args, root_fs)
+        execute_command(
+            &self.command,
+            &self.command_args,
+            root_fs,
+            &ProcessConfig::default(),
+        )
     }
 }
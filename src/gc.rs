@@ -0,0 +1,151 @@
+//! Mark-and-sweep garbage collection of the image and layer stores
+//!
+//! The roots are the image index and the containers. Blobs the index does not reach, interrupted downloads
+//! and unpacked layers no container is stacked on are garbage.
+//!
+//! Blobs of a pull in progress are only reachable once the pull updates the index, so collect while
+//! holding [`lock_pulls_out`](crate::image_store::lock_pulls_out).
+
+use std::{collections::HashSet, path::PathBuf};
+
+use crate::{
+    image_store::{
+        blob_path, read_config, referenced_blobs, remove_stale_locks, stored_blobs, ImageIndex,
+    },
+    images::human_size,
+    layer_store,
+    pull_image::models::ImageManifest,
+    CONTAINERS,
+};
+
+#[derive(Debug)]
+pub struct Garbage {
+    /// Digest of a blob or diff ID of an unpacked layer
+    pub name: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Find everything unreachable from the image index and from the containers that pass `keep_container`
+pub fn collect(keep_container: impl Fn(&str) -> bool) -> anyhow::Result<Vec<Garbage>> {
+    // Mark
+    let blobs_in_use = referenced_blobs(&ImageIndex::load()?);
+    let container_in_use = |name: &str| CONTAINERS.join(name).exists() && keep_container(name);
+
+    // Sweep candidates
+    let mut garbage = vec![];
+    for (digest, path) in stored_blobs()? {
+        if !blobs_in_use.contains(&digest) {
+            garbage.push(Garbage::new(digest, path));
+        }
+    }
+    for (diff_id, path) in layer_store::unreferenced(container_in_use)? {
+        garbage.push(Garbage::new(diff_id, path));
+    }
+    Ok(garbage)
+}
+
+/// What the image with the manifest `manifest_digest` leaves behind once it is out of the index
+///
+/// Unlike [`collect`], blobs of other images and interrupted downloads are left alone.
+/// The manifest is read before the image is untagged, as nothing would reach its blobs otherwise.
+pub fn collect_image(
+    manifest_digest: &str,
+    manifest: &ImageManifest,
+) -> anyhow::Result<Vec<Garbage>> {
+    let config_digest = manifest.config().digest();
+    let diff_ids = match read_config(config_digest) {
+        Ok(config) => config.rootfs().diff_ids().clone(),
+        Err(_) => vec![],
+    };
+
+    // Blobs shared with images still in the index stay
+    let blobs_in_use = referenced_blobs(&ImageIndex::load()?);
+    let blobs = [manifest_digest, config_digest].into_iter().chain(
+        manifest
+            .layers()
+            .iter()
+            .map(|layer| layer.digest().as_str()),
+    );
+    let mut garbage = vec![];
+    let mut seen = HashSet::new();
+    for digest in blobs.filter(|digest| seen.insert(*digest)) {
+        let path = blob_path(digest)?;
+        if !blobs_in_use.contains(digest) && path.exists() {
+            garbage.push(Garbage::new(digest.to_string(), path));
+        }
+    }
+
+    // Unpacked layers still used by containers stay until those are removed
+    let container_in_use = |name: &str| CONTAINERS.join(name).exists();
+    for (diff_id, path) in layer_store::unreferenced(container_in_use)? {
+        if diff_ids.contains(&diff_id) {
+            garbage.push(Garbage::new(diff_id, path));
+        }
+    }
+    Ok(garbage)
+}
+
+/// Delete `garbage` along with the locks of whatever is gone, or only list it with `dry_run`
+pub fn sweep(garbage: &[Garbage], dry_run: bool) -> anyhow::Result<()> {
+    for item in garbage {
+        if !dry_run {
+            match std::fs::symlink_metadata(&item.path) {
+                Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&item.path)?,
+                Ok(_) => std::fs::remove_file(&item.path)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let verb = if dry_run { "Would delete" } else { "Deleted" };
+        println!("{verb}: {} ({})", item.name, human_size(item.size as usize));
+    }
+    if !dry_run {
+        remove_stale_locks("blobs", |digest| {
+            blob_path(digest).is_ok_and(|path| path.exists())
+        })?;
+        remove_stale_locks("layers", layer_store::contains)?;
+    }
+    let total = garbage.iter().map(|item| item.size).sum::<u64>();
+    let label = if dry_run { "reclaimable" } else { "reclaimed" };
+    println!("Total {label} space: {}", human_size(total as usize));
+    Ok(())
+}
+
+impl Garbage {
+    fn new(name: String, path: PathBuf) -> Self {
+        let size = disk_usage(&path);
+        Self { name, path, size }
+    }
+}
+
+fn disk_usage(path: &std::path::Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    std::fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| disk_usage(&entry.path()))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_usage() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("diff/etc")).unwrap();
+        std::fs::write(dir.path().join("diff/etc/passwd"), b"root").unwrap();
+        std::fs::write(dir.path().join("diff/motd"), b"hi").unwrap();
+        assert_eq!(disk_usage(dir.path()), 6);
+        assert_eq!(disk_usage(&dir.path().join("diff/motd")), 2);
+        assert_eq!(disk_usage(&dir.path().join("missing")), 0);
+    }
+}
@@ -0,0 +1,71 @@
+use clap::{Args, Subcommand};
+
+use crate::{
+    gc,
+    image_reference::ImageReference,
+    image_store::{blob_path, lock_pulls_out, manifest_digest, read_manifest},
+};
+
+#[derive(Debug, Args)]
+pub struct ImageArgs {
+    #[clap(subcommand)]
+    sub_command: ImageCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum ImageCommand {
+    Inspect(InspectArgs),
+    Prune(PruneArgs),
+}
+
+impl ImageArgs {
+    pub fn run(self) -> anyhow::Result<()> {
+        match self.sub_command {
+            ImageCommand::Inspect(inspect) => inspect.run(),
+            ImageCommand::Prune(prune) => prune.run(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    pub images: Vec<ImageReference>,
+}
+
+impl InspectArgs {
+    // Usage: your_docker.sh image inspect <image> ...
+    pub fn run(self) -> anyhow::Result<()> {
+        let mut output = vec![];
+        for image in &self.images {
+            let manifest_digest = manifest_digest(image)?;
+            let manifest = read_manifest(&manifest_digest)?;
+            let read_json = |digest: &str| -> anyhow::Result<serde_json::Value> {
+                Ok(serde_json::from_slice(&std::fs::read(blob_path(digest)?)?)?)
+            };
+            output.push(serde_json::json!({
+                "Name": image.to_string(),
+                "Digest": manifest_digest,
+                "Manifest": read_json(&manifest_digest)?,
+                "Config": read_json(manifest.config().digest())?,
+            }));
+        }
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct PruneArgs {
+    /// List what would be deleted without deleting anything
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+impl PruneArgs {
+    // Usage: your_docker.sh image prune [--dry-run]
+    pub fn run(self) -> anyhow::Result<()> {
+        let _lock = lock_pulls_out()?;
+        let garbage = gc::collect(|_| true)?;
+        gc::sweep(&garbage, self.dry_run)
+    }
+}
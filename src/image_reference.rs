@@ -0,0 +1,269 @@
+// https://github.com/distribution/reference/blob/main/reference.go
+
+use std::{fmt, str::FromStr};
+
+use getset::Getters;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+pub const DOCKER_HUB_DOMAIN: &str = "docker.io";
+const LEGACY_DOCKER_HUB_DOMAIN: &str = "index.docker.io";
+const OFFICIAL_REPOSITORY_NAMESPACE: &str = "library";
+const DEFAULT_TAG: &str = "latest";
+const NAME_TOTAL_LENGTH_MAX: usize = 255;
+
+static DOMAIN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?)(?:\.(?:[a-zA-Z0-9](?:[a-zA-Z0-9-]*[a-zA-Z0-9])?))*(?::[0-9]+)?$",
+    )
+    .unwrap()
+});
+static PATH_COMPONENT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[a-z0-9]+(?:(?:[._]|__|[-]+)[a-z0-9]+)*$").unwrap());
+static TAG: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[\w][\w.-]{0,127}$").unwrap());
+static DIGEST: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[a-z0-9]+(?:[.+_-][a-z0-9]+)*:[a-fA-F0-9]{32,}$").unwrap());
+
+/// A fully qualified image reference, e.g. `docker.io/library/busybox:latest`
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct ImageReference {
+    /// Registry host with an optional port
+    #[getset(get = "pub")]
+    domain: String,
+    /// Path of the repository inside the registry
+    #[getset(get = "pub")]
+    repository: String,
+    #[getset(get = "pub")]
+    tag: Option<String>,
+    #[getset(get = "pub")]
+    digest: Option<String>,
+}
+
+impl ImageReference {
+    /// The tag or digest to request the manifest by, preferring the digest pin
+    pub fn reference(&self) -> &str {
+        match (&self.digest, &self.tag) {
+            (Some(digest), _) => digest,
+            (None, Some(tag)) => tag,
+            (None, None) => DEFAULT_TAG,
+        }
+    }
+
+    /// `domain/repository`, identifying the repository across registries
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.domain, self.repository)
+    }
+
+    /// Base URL of the registry serving this image
+    pub fn registry_url(&self, default_registry: &str) -> String {
+        registry_url(&self.domain, default_registry)
+    }
+}
+
+/// Base URL of the registry at `domain`
+///
+/// Docker Hub images go to `default_registry`.
+/// Registries on the loopback interface are spoken to over plain HTTP, like Docker does by default.
+pub fn registry_url(domain: &str, default_registry: &str) -> String {
+    if domain == DOCKER_HUB_DOMAIN {
+        return default_registry.to_string();
+    }
+    let host = domain
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(domain);
+    match host {
+        "localhost" | "127.0.0.1" => format!("http://{domain}"),
+        _ => format!("https://{domain}"),
+    }
+}
+
+impl fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.domain, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{tag}")?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{digest}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ImageReference {
+    type Err = ImageReferenceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(ImageReferenceError::Empty);
+        }
+
+        // reference <- name ( ":" tag )? ( "@" digest )?
+        let (name, digest) = match s.split_once('@') {
+            Some((name, digest)) => {
+                if !DIGEST.is_match(digest) {
+                    return Err(ImageReferenceError::InvalidDigest(digest.to_string()));
+                }
+                (name, Some(digest.to_string()))
+            }
+            None => (s, None),
+        };
+        let (name, tag) = match name.rsplit_once(':') {
+            // The colon belongs to a port in the domain
+            Some((_, tag)) if tag.contains('/') => (name, None),
+            Some((name, tag)) => {
+                if !TAG.is_match(tag) {
+                    return Err(ImageReferenceError::InvalidTag(tag.to_string()));
+                }
+                (name, Some(tag.to_string()))
+            }
+            None => (name, None),
+        };
+        if name.len() > NAME_TOTAL_LENGTH_MAX {
+            return Err(ImageReferenceError::NameTooLong(name.len()));
+        }
+
+        // The first component is a domain only if it looks like a host
+        let (domain, repository) = match name.split_once('/') {
+            Some((domain, repository))
+                if domain.contains(['.', ':'])
+                    || domain == "localhost"
+                    || domain.to_lowercase() != domain =>
+            {
+                if !DOMAIN.is_match(domain) {
+                    return Err(ImageReferenceError::InvalidDomain(domain.to_string()));
+                }
+                (domain, repository)
+            }
+            _ => (DOCKER_HUB_DOMAIN, name),
+        };
+        let domain = match domain {
+            LEGACY_DOCKER_HUB_DOMAIN => DOCKER_HUB_DOMAIN,
+            _ => domain,
+        };
+
+        if let Some(component) = repository
+            .split('/')
+            .find(|component| !PATH_COMPONENT.is_match(component))
+        {
+            return Err(ImageReferenceError::InvalidPathComponent(
+                component.to_string(),
+            ));
+        }
+        let repository = match domain == DOCKER_HUB_DOMAIN && !repository.contains('/') {
+            true => format!("{OFFICIAL_REPOSITORY_NAMESPACE}/{repository}"),
+            false => repository.to_string(),
+        };
+
+        // Neither tag nor digest means `latest`
+        let tag = match (&tag, &digest) {
+            (None, None) => Some(DEFAULT_TAG.to_string()),
+            _ => tag,
+        };
+
+        Ok(Self {
+            domain: domain.to_string(),
+            repository,
+            tag,
+            digest,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ImageReferenceError {
+    #[error("image reference is empty")]
+    Empty,
+    #[error("image name is {0} characters long, longer than 255")]
+    NameTooLong(usize),
+    #[error("invalid registry domain `{0}`")]
+    InvalidDomain(String),
+    #[error("invalid repository path component `{0}`")]
+    InvalidPathComponent(String),
+    #[error("invalid tag `{0}`")]
+    InvalidTag(String),
+    #[error("invalid digest `{0}`")]
+    InvalidDigest(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:7d4bd38e3b6b1c8bbd8b8e1e5e2d2e0b4b1ff4d68b1b2d0b3c3e38a1d4e2f1a0";
+
+    #[test]
+    fn test_official_image() {
+        let reference: ImageReference = "busybox".parse().unwrap();
+        assert_eq!(reference.domain(), "docker.io");
+        assert_eq!(reference.repository(), "library/busybox");
+        assert_eq!(reference.tag().as_deref(), Some("latest"));
+        assert_eq!(reference.digest(), &None);
+        assert_eq!(reference.reference(), "latest");
+    }
+
+    #[test]
+    fn test_namespaced_image_with_tag() {
+        let reference: ImageReference = "codecrafters/explorer:v18".parse().unwrap();
+        assert_eq!(reference.domain(), "docker.io");
+        assert_eq!(reference.repository(), "codecrafters/explorer");
+        assert_eq!(reference.reference(), "v18");
+    }
+
+    #[test]
+    fn test_registry_with_port() {
+        let reference: ImageReference = "localhost:5000/foo:1".parse().unwrap();
+        assert_eq!(reference.domain(), "localhost:5000");
+        assert_eq!(reference.repository(), "foo");
+        assert_eq!(reference.reference(), "1");
+        assert_eq!(
+            reference.registry_url("https://registry.hub.docker.com"),
+            "http://localhost:5000"
+        );
+
+        let reference: ImageReference = "ghcr.io/a/b/c".parse().unwrap();
+        assert_eq!(reference.domain(), "ghcr.io");
+        assert_eq!(reference.repository(), "a/b/c");
+        assert_eq!(reference.registry_url(""), "https://ghcr.io");
+    }
+
+    #[test]
+    fn test_digest() {
+        let reference: ImageReference = format!("alpine@{DIGEST}").parse().unwrap();
+        assert_eq!(reference.repository(), "library/alpine");
+        assert_eq!(reference.tag(), &None);
+        assert_eq!(reference.reference(), DIGEST);
+
+        let reference: ImageReference = format!("index.docker.io/alpine:3@{DIGEST}")
+            .parse()
+            .unwrap();
+        assert_eq!(reference.domain(), "docker.io");
+        assert_eq!(reference.tag().as_deref(), Some("3"));
+        assert_eq!(reference.reference(), DIGEST);
+        assert_eq!(
+            reference.to_string(),
+            format!("docker.io/library/alpine:3@{DIGEST}")
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            "".parse::<ImageReference>(),
+            Err(ImageReferenceError::Empty)
+        );
+        assert!(matches!(
+            "Busybox".parse::<ImageReference>(),
+            Err(ImageReferenceError::InvalidPathComponent(_))
+        ));
+        assert!(matches!(
+            "busybox:-bad".parse::<ImageReference>(),
+            Err(ImageReferenceError::InvalidTag(_))
+        ));
+        assert!(matches!(
+            "busybox@sha256:abc".parse::<ImageReference>(),
+            Err(ImageReferenceError::InvalidDigest(_))
+        ));
+    }
+}
@@ -0,0 +1,355 @@
+//! Content-addressable image store
+//!
+//! ```text
+//! blobs/<algorithm>/<hex>   manifests, configs and layers, deduplicated by digest
+//! repositories.json         `domain/repository` → tag or digest → manifest digest
+//! locks/blobs/<algorithm>/<hex>   held while the blob is downloaded
+//! locks/layers/<algorithm>/<hex>  held while the unpacked layer is referenced, unpacked or freed
+//! locks/pulls               shared by pulls, exclusive while collecting garbage
+//! ```
+
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    image_reference::ImageReference,
+    pull_image::models::{ImageConfigFile, ImageManifest},
+    BASE_DIR,
+};
+
+static BLOBS: once_cell::sync::Lazy<std::path::PathBuf> =
+    once_cell::sync::Lazy::new(|| BASE_DIR.join("blobs"));
+static REPOSITORIES_FILE: once_cell::sync::Lazy<std::path::PathBuf> =
+    once_cell::sync::Lazy::new(|| BASE_DIR.join("repositories.json"));
+static LOCKS: once_cell::sync::Lazy<std::path::PathBuf> =
+    once_cell::sync::Lazy::new(|| BASE_DIR.join("locks"));
+
+pub fn blob_path(digest: &str) -> anyhow::Result<std::path::PathBuf> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("malformed digest `{digest}`"))?;
+    Ok(BLOBS.join(algorithm).join(hex))
+}
+
+fn lock_path(kind: &str, digest: &str) -> anyhow::Result<std::path::PathBuf> {
+    let (algorithm, hex) = digest
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("malformed digest `{digest}`"))?;
+    let lock_path = LOCKS.join(kind).join(algorithm).join(hex);
+    std::fs::create_dir_all(lock_path.parent().unwrap())?;
+    Ok(lock_path)
+}
+
+/// Wait until no other task or process downloads the blob `digest`, and keep them off it until dropped
+pub async fn lock_blob(digest: &str) -> anyhow::Result<FileLock> {
+    let lock_path = lock_path("blobs", digest)?;
+    tokio::task::spawn_blocking(move || FileLock::acquire(&lock_path, libc::LOCK_EX))
+        .await?
+        .with_context(|| format!("failed to lock blob `{digest}`"))
+}
+
+/// Wait until no other process references, unpacks or frees the unpacked layer `diff_id`, and keep them off it
+/// until dropped
+pub fn lock_layer(diff_id: &str) -> anyhow::Result<FileLock> {
+    FileLock::acquire(&lock_path("layers", diff_id)?, libc::LOCK_EX)
+        .with_context(|| format!("failed to lock layer `{diff_id}`"))
+}
+
+/// Held for the whole of a pull, whose blobs the index does not reach until it is done
+///
+/// Pulls do not wait for each other, only for garbage collection.
+pub async fn lock_pull() -> anyhow::Result<FileLock> {
+    std::fs::create_dir_all(LOCKS.as_path())?;
+    tokio::task::spawn_blocking(|| FileLock::acquire(&LOCKS.join("pulls"), libc::LOCK_SH))
+        .await?
+        .context("failed to lock the image store for pulling")
+}
+
+/// Wait for the pulls in progress and keep new ones from starting until dropped
+pub fn lock_pulls_out() -> anyhow::Result<FileLock> {
+    std::fs::create_dir_all(LOCKS.as_path())?;
+    FileLock::acquire(&LOCKS.join("pulls"), libc::LOCK_EX)
+        .context("failed to lock the image store for garbage collection")
+}
+
+/// Every file in the blob store along with the digest it is stored under
+///
+/// Interrupted downloads show up with a `.partial` suffix on their digest.
+pub fn stored_blobs() -> anyhow::Result<Vec<(String, std::path::PathBuf)>> {
+    let mut blobs = vec![];
+    let algorithms = match std::fs::read_dir(BLOBS.as_path()) {
+        Ok(algorithms) => algorithms,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(blobs),
+        Err(e) => return Err(e.into()),
+    };
+    for algorithm in algorithms {
+        let algorithm = algorithm?;
+        for blob in std::fs::read_dir(algorithm.path())? {
+            let blob = blob?;
+            let digest = format!(
+                "{}:{}",
+                algorithm.file_name().to_string_lossy(),
+                blob.file_name().to_string_lossy()
+            );
+            blobs.push((digest, blob.path()));
+        }
+    }
+    Ok(blobs)
+}
+
+/// Maps image references to the digests of their manifests
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImageIndex {
+    repositories: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl ImageIndex {
+    pub fn load() -> anyhow::Result<Self> {
+        match std::fs::read(REPOSITORIES_FILE.as_path()) {
+            Ok(index) => Ok(serde_json::from_slice(&index)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Load, modify and save the index while holding a lock on it
+    pub fn update<T>(f: impl FnOnce(&mut Self) -> T) -> anyhow::Result<T> {
+        std::fs::create_dir_all(BASE_DIR.as_path())?;
+        let _lock = FileLock::acquire(&REPOSITORIES_FILE.with_extension("lock"), libc::LOCK_EX)
+            .context("failed to lock the image index")?;
+        let mut index = Self::load()?;
+        let output = f(&mut index);
+        index.save()?;
+        Ok(output)
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        // Write aside and rename so that readers never see a half written index
+        let tmp_path = REPOSITORIES_FILE.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp_path, REPOSITORIES_FILE.as_path())?;
+        Ok(())
+    }
+
+    pub fn get(&self, image: &ImageReference) -> Option<&str> {
+        self.repositories
+            .get(&image.name())?
+            .get(image.reference())
+            .map(String::as_str)
+    }
+
+    pub fn insert(&mut self, image: &ImageReference, manifest_digest: &str) {
+        self.repositories
+            .entry(image.name())
+            .or_default()
+            .insert(image.reference().to_string(), manifest_digest.to_string());
+    }
+
+    pub fn remove(&mut self, image: &ImageReference) -> Option<String> {
+        let name = image.name();
+        let references = self.repositories.get_mut(&name)?;
+        let manifest_digest = references.remove(image.reference());
+        if references.is_empty() {
+            self.repositories.remove(&name);
+        }
+        manifest_digest
+    }
+
+    /// All `(name, tag or digest, manifest digest)` entries
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.repositories.iter().flat_map(|(name, references)| {
+            references.iter().map(move |(reference, digest)| {
+                (name.as_str(), reference.as_str(), digest.as_str())
+            })
+        })
+    }
+}
+
+/// `flock` on a file, released when dropped
+pub struct FileLock(std::fs::File);
+
+impl FileLock {
+    /// `operation` is `LOCK_EX` or `LOCK_SH`
+    fn acquire(path: &std::path::Path, operation: libc::c_int) -> std::io::Result<Self> {
+        loop {
+            if let Some(lock) = Self::lock(path, operation)? {
+                return Ok(lock);
+            }
+        }
+    }
+
+    /// `None` if the lock file was removed while we waited for it, see [`remove_stale_locks`]
+    fn lock(path: &std::path::Path, operation: libc::c_int) -> std::io::Result<Option<Self>> {
+        use std::os::{fd::AsRawFd, unix::fs::MetadataExt};
+
+        let file = std::fs::File::options()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        let res = unsafe { libc::flock(file.as_raw_fd(), operation) };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let locked = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.ino() == file.metadata()?.ino(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        Ok(locked.then_some(Self(file)))
+    }
+}
+
+/// Delete the lock files of the blobs or layers, by `kind`, that are not `stored` anymore
+///
+/// Locks someone holds are left alone.
+pub fn remove_stale_locks(kind: &str, stored: impl Fn(&str) -> bool) -> anyhow::Result<()> {
+    let algorithms = match std::fs::read_dir(LOCKS.join(kind)) {
+        Ok(algorithms) => algorithms,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for algorithm in algorithms {
+        let algorithm = algorithm?;
+        for lock in std::fs::read_dir(algorithm.path())? {
+            let lock = lock?;
+            let digest = format!(
+                "{}:{}",
+                algorithm.file_name().to_string_lossy(),
+                lock.file_name().to_string_lossy()
+            );
+            if stored(&digest) {
+                continue;
+            }
+            match FileLock::lock(&lock.path(), libc::LOCK_EX | libc::LOCK_NB) {
+                // Removed while still locked, so that whoever opened it meanwhile tries again with a new one
+                Ok(Some(_lock)) => std::fs::remove_file(lock.path())?,
+                Ok(None) => (),
+                Err(e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => (),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        use std::os::fd::AsRawFd;
+
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Digest of the manifest of an already pulled `image`
+pub fn manifest_digest(image: &ImageReference) -> anyhow::Result<String> {
+    ImageIndex::load()?
+        .get(image)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("image `{image}` has not been pulled"))
+}
+
+pub fn read_manifest(manifest_digest: &str) -> anyhow::Result<ImageManifest> {
+    let manifest = std::fs::read(blob_path(manifest_digest)?)
+        .with_context(|| format!("manifest `{manifest_digest}` is missing from the store"))?;
+    Ok(serde_json::from_slice(&manifest)?)
+}
+
+pub fn read_config(config_digest: &str) -> anyhow::Result<ImageConfigFile> {
+    let config = std::fs::read(blob_path(config_digest)?)
+        .with_context(|| format!("config `{config_digest}` is missing from the store"))?;
+    Ok(serde_json::from_slice(&config)?)
+}
+
+/// Manifest of an already pulled `image`
+pub fn image_manifest(image: &ImageReference) -> anyhow::Result<ImageManifest> {
+    read_manifest(&manifest_digest(image)?)
+}
+
+/// Config of an already pulled `image`
+pub fn image_config(image: &ImageReference) -> anyhow::Result<ImageConfigFile> {
+    read_config(image_manifest(image)?.config().digest())
+}
+
+/// Digests of the manifests, configs and layers of the images in `index`
+pub fn referenced_blobs(index: &ImageIndex) -> HashSet<String> {
+    let mut blobs = HashSet::new();
+    for (_, _, manifest_digest) in index.iter() {
+        blobs.insert(manifest_digest.to_string());
+        let Ok(manifest) = read_manifest(manifest_digest) else {
+            continue;
+        };
+        blobs.insert(manifest.config().digest().to_string());
+        blobs.extend(
+            manifest
+                .layers()
+                .iter()
+                .map(|layer| layer.digest().to_string()),
+        );
+    }
+    blobs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_index() {
+        let latest: ImageReference = "busybox".parse().unwrap();
+        let pinned: ImageReference = "busybox:1.36".parse().unwrap();
+        let mut index = ImageIndex::default();
+        index.insert(&latest, "sha256:a");
+        index.insert(&pinned, "sha256:a");
+        assert_eq!(index.get(&latest), Some("sha256:a"));
+        assert_eq!(
+            index.iter().collect::<Vec<_>>(),
+            [
+                ("docker.io/library/busybox", "1.36", "sha256:a"),
+                ("docker.io/library/busybox", "latest", "sha256:a"),
+            ]
+        );
+
+        assert_eq!(index.remove(&latest).as_deref(), Some("sha256:a"));
+        assert_eq!(index.remove(&latest), None);
+        assert_eq!(index.remove(&pinned).as_deref(), Some("sha256:a"));
+        assert!(index.repositories.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lock_blob() {
+        let digest = format!("sha256:test-lock-{}", std::process::id());
+        let lock = lock_blob(&digest).await.unwrap();
+        let waiting = tokio::spawn({
+            let digest = digest.clone();
+            async move { lock_blob(&digest).await.unwrap() }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+        drop(lock);
+        tokio::time::timeout(std::time::Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_stale_locks() {
+        let held = format!("sha256:test-held-{}", std::process::id());
+        let stale = format!("sha256:test-stale-{}", std::process::id());
+        let lock = lock_blob(&held).await.unwrap();
+        drop(lock_blob(&stale).await.unwrap());
+
+        // Leave alone the locks of other tests and of the blobs actually stored
+        remove_stale_locks("blobs", |digest| digest != held && digest != stale).unwrap();
+        assert!(lock_path("blobs", &held).unwrap().exists());
+        assert!(!lock_path("blobs", &stale).unwrap().exists());
+
+        drop(lock);
+        remove_stale_locks("blobs", |digest| digest != held).unwrap();
+        assert!(!lock_path("blobs", &held).unwrap().exists());
+    }
+}
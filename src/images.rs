@@ -0,0 +1,94 @@
+use clap::Args;
+
+use crate::image_store::{read_config, read_manifest, ImageIndex};
+
+#[derive(Debug, Args)]
+pub struct ImagesArgs {}
+
+impl ImagesArgs {
+    // Usage: your_docker.sh images
+    pub fn run(self) -> anyhow::Result<()> {
+        let index = ImageIndex::load()?;
+        println!(
+            "{:<40} {:<20} {:<20} {:>10}  CREATED",
+            "REPOSITORY", "TAG", "DIGEST", "SIZE"
+        );
+        for (name, reference, manifest_digest) in index.iter() {
+            // Pulled by digest
+            let tag = match reference.contains(':') {
+                true => "<none>",
+                false => reference,
+            };
+            let digest: String = manifest_digest.chars().take("sha256:".len() + 12).collect();
+            let (size, created) = match read_manifest(manifest_digest) {
+                Ok(manifest) => {
+                    let size = manifest.config().size()
+                        + manifest.layers().iter().map(|l| l.size()).sum::<usize>();
+                    let created = read_config(manifest.config().digest())
+                        .ok()
+                        .and_then(|config| config.created().clone());
+                    (human_size(size), format_created(created.as_deref()))
+                }
+                Err(_) => ("<missing>".to_string(), String::new()),
+            };
+            println!("{name:<40} {tag:<20} {digest:<20} {size:>10}  {created}");
+        }
+        Ok(())
+    }
+}
+
+/// Size in decimal units with three significant digits, like `docker images`
+pub(crate) fn human_size(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    let decimals = match size {
+        size if size >= 100.0 => 0,
+        size if size >= 10.0 => 1,
+        _ => 2,
+    };
+    let size = format!("{size:.decimals$}");
+    let size = match size.contains('.') {
+        true => size.trim_end_matches('0').trim_end_matches('.'),
+        false => &size,
+    };
+    format!("{size}{}", UNITS[unit])
+}
+
+/// `2023-10-31T12:34:56.789Z` → `2023-10-31 12:34:56`
+fn format_created(created: Option<&str>) -> String {
+    let Some(created) = created else {
+        return "<unknown>".to_string();
+    };
+    created
+        .get(.."YYYY-MM-DDTHH:MM:SS".len())
+        .map(|created| created.replace('T', " "))
+        .unwrap_or_else(|| created.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(999), "999B");
+        assert_eq!(human_size(1_000), "1kB");
+        assert_eq!(human_size(4_261_000), "4.26MB");
+        assert_eq!(human_size(77_800_000), "77.8MB");
+        assert_eq!(human_size(123_456_789), "123MB");
+    }
+
+    #[test]
+    fn test_format_created() {
+        assert_eq!(
+            format_created(Some("2023-10-31T12:34:56.789012Z")),
+            "2023-10-31 12:34:56"
+        );
+        assert_eq!(format_created(None), "<unknown>");
+    }
+}
@@ -0,0 +1,159 @@
+//! Unpacked layers shared by all containers, keyed by diff ID
+//!
+//! ```text
+//! unpacked/<algorithm>/<hex>/diff/         read-only lower dir
+//! unpacked/<algorithm>/<hex>/refs/<name>   one file per container using the layer
+//! ```
+
+use std::{future::Future, path::PathBuf};
+
+use crate::{image_store::lock_layer, process_alive, UNPACKED_LAYER_DIR};
+
+const DIFF_DIR: &str = "diff";
+const REFS_DIR: &str = "refs";
+
+fn layer_dir(diff_id: &str) -> anyhow::Result<PathBuf> {
+    let (algorithm, hex) = diff_id
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("malformed diff ID `{diff_id}`"))?;
+    Ok(UNPACKED_LAYER_DIR.join(algorithm).join(hex))
+}
+
+/// Whether the layer `diff_id` is in the store, unpacked or about to be
+pub fn contains(diff_id: &str) -> bool {
+    layer_dir(diff_id).is_ok_and(|layer_dir| layer_dir.exists())
+}
+
+/// Reference the layer `diff_id` from `container_name`, unpacking it with `unpack` if it is not in the store yet
+///
+/// Returns the lower dir of the layer.
+pub async fn acquire<F, Fut>(
+    diff_id: &str,
+    container_name: &str,
+    unpack: F,
+) -> anyhow::Result<PathBuf>
+where
+    F: FnOnce(PathBuf) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let layer_dir = layer_dir(diff_id)?;
+    let diff_dir = layer_dir.join(DIFF_DIR);
+
+    // Keep other containers from freeing the layer between referencing it and mounting it
+    let lock_diff_id = diff_id.to_string();
+    let _lock = tokio::task::spawn_blocking(move || lock_layer(&lock_diff_id)).await??;
+
+    let refs_dir = layer_dir.join(REFS_DIR);
+    tokio::fs::create_dir_all(&refs_dir).await?;
+    tokio::fs::write(refs_dir.join(container_name), b"").await?;
+
+    if !diff_dir.exists() {
+        // Unpack aside and move into place so that a half unpacked layer is never used
+        let tmp_dir = layer_dir.join(format!("{DIFF_DIR}.{}.tmp", std::process::id()));
+        let _ = tokio::fs::remove_dir_all(&tmp_dir).await;
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        if let Err(e) = unpack(tmp_dir.clone()).await {
+            let _ = tokio::fs::remove_dir_all(&tmp_dir).await;
+            remove_ref(&layer_dir, container_name)?;
+            return Err(e);
+        }
+        tokio::fs::rename(&tmp_dir, &diff_dir).await?;
+    }
+    Ok(diff_dir)
+}
+
+/// Drop the references of `container_name` to its lower dirs, freeing the layers no container uses anymore
+pub fn release(container_name: &str, lower_dirs: &[PathBuf]) -> anyhow::Result<()> {
+    for lower_dir in lower_dirs {
+        let Some(layer_dir) = lower_dir.parent() else {
+            continue;
+        };
+        let _lock = lock_layer(&diff_id(layer_dir))?;
+        remove_ref(layer_dir, container_name)?;
+    }
+    Ok(())
+}
+
+/// Diff ID of the layer stored in `layer_dir`
+fn diff_id(layer_dir: &std::path::Path) -> String {
+    let name = |path: Option<&std::path::Path>| {
+        path.and_then(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    format!("{}:{}", name(layer_dir.parent()), name(Some(layer_dir)))
+}
+
+/// Drop the reference of `container_name` to the layer in `layer_dir` and free the layer if it was the last one
+///
+/// The layer must be locked.
+fn remove_ref(layer_dir: &std::path::Path, container_name: &str) -> anyhow::Result<()> {
+    match std::fs::remove_file(layer_dir.join(REFS_DIR).join(container_name)) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    free_if_unreferenced(layer_dir)
+}
+
+/// Layers none of whose referencing containers pass `in_use`, and unpacks left behind by processes that died
+///
+/// Each entry is the diff ID, or the diff ID and the leftover, along with the directory to delete.
+pub fn unreferenced(in_use: impl Fn(&str) -> bool) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut unreferenced = vec![];
+    let algorithms = match std::fs::read_dir(UNPACKED_LAYER_DIR.as_path()) {
+        Ok(algorithms) => algorithms,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(unreferenced),
+        Err(e) => return Err(e.into()),
+    };
+    for algorithm in algorithms {
+        let algorithm = algorithm?;
+        for layer in std::fs::read_dir(algorithm.path())? {
+            let layer = layer?;
+            let diff_id = format!(
+                "{}:{}",
+                algorithm.file_name().to_string_lossy(),
+                layer.file_name().to_string_lossy()
+            );
+            let referenced = match std::fs::read_dir(layer.path().join(REFS_DIR)) {
+                Ok(refs) => refs
+                    .filter_map(Result::ok)
+                    .any(|r| in_use(&r.file_name().to_string_lossy())),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+                Err(e) => return Err(e.into()),
+            };
+            if !referenced {
+                unreferenced.push((diff_id, layer.path()));
+                continue;
+            }
+            for entry in std::fs::read_dir(layer.path())? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let unpacking_pid = name
+                    .strip_prefix(&format!("{DIFF_DIR}."))
+                    .and_then(|name| name.strip_suffix(".tmp"))
+                    .and_then(|pid| pid.parse().ok());
+                if unpacking_pid.is_some_and(|pid| !process_alive(pid)) {
+                    unreferenced.push((format!("{diff_id}/{name}"), entry.path()));
+                }
+            }
+        }
+    }
+    Ok(unreferenced)
+}
+
+fn free_if_unreferenced(layer_dir: &std::path::Path) -> anyhow::Result<()> {
+    if !layer_dir.exists() {
+        return Ok(());
+    }
+    let refs_dir = layer_dir.join(REFS_DIR);
+    let unreferenced = match std::fs::read_dir(&refs_dir) {
+        Ok(mut refs) => refs.next().is_none(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e.into()),
+    };
+    if unreferenced {
+        std::fs::remove_dir_all(layer_dir)?;
+    }
+    Ok(())
+}
@@ -1,22 +1,40 @@
 use std::os::unix::process::CommandExt;
 
+pub mod credentials;
+pub mod digest;
 pub mod exec;
+pub mod gc;
+pub mod image;
+pub mod image_reference;
+pub mod image_store;
+pub mod images;
+pub mod layer_store;
+pub mod login;
+pub mod logout;
 pub mod ls;
+pub mod mirrors;
 #[cfg(target_os = "linux")]
 pub mod mounting;
+pub mod platform;
+pub mod pull;
 pub mod pull_image;
+pub mod retry;
 pub mod rm;
 pub mod rmi;
 pub mod run;
+pub mod system;
+pub mod tag;
 pub mod token_auth;
+pub mod user;
+pub mod whiteout;
 pub mod www_authenticate;
 
 static BASE_DIR: once_cell::sync::Lazy<std::path::PathBuf> =
     once_cell::sync::Lazy::new(|| std::path::PathBuf::from("/tmp/mydocker"));
 static CONTAINERS: once_cell::sync::Lazy<std::path::PathBuf> =
     once_cell::sync::Lazy::new(|| BASE_DIR.join("containers"));
-static PACKED_LAYER_DIR: once_cell::sync::Lazy<std::path::PathBuf> =
-    once_cell::sync::Lazy::new(|| BASE_DIR.join("layers"));
+static UNPACKED_LAYER_DIR: once_cell::sync::Lazy<std::path::PathBuf> =
+    once_cell::sync::Lazy::new(|| BASE_DIR.join("unpacked"));
 
 fn container_dir(name: &str) -> std::path::PathBuf {
     CONTAINERS.join(name)
@@ -49,9 +67,35 @@ fn overlay_fs_upper_dir(name: &str) -> std::path::PathBuf {
     overlay_fs_writable_layers_dir(name).join("upper")
 }
 
-#[allow(dead_code)]
-fn overlay_fs_lower_dir(name: &str) -> std::path::PathBuf {
-    overlay_layer_dir(name).join("lower")
+/// Lists the lower dirs of a container bottom layer first, in manifest order
+fn overlay_fs_lower_dirs_file(name: &str) -> std::path::PathBuf {
+    overlay_layer_dir(name).join("lower.json")
+}
+
+/// Short symlinks to the lower dirs, named by position, that overlayfs is given instead of the long store paths
+fn overlay_fs_lower_links_dir(name: &str) -> std::path::PathBuf {
+    overlay_layer_dir(name).join("l")
+}
+
+fn write_lower_dirs(name: &str, lower_dirs: &[std::path::PathBuf]) -> anyhow::Result<()> {
+    let file = overlay_fs_lower_dirs_file(name);
+    std::fs::create_dir_all(file.parent().unwrap())?;
+    std::fs::write(file, serde_json::to_vec(lower_dirs)?)?;
+    Ok(())
+}
+
+fn read_lower_dirs(name: &str) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    let lower_dirs = std::fs::read(overlay_fs_lower_dirs_file(name))?;
+    Ok(serde_json::from_slice(&lower_dirs)?)
+}
+
+/// Let go of the shared layers the container was stacked on
+fn release_lower_dirs(name: &str) -> anyhow::Result<()> {
+    match read_lower_dirs(name) {
+        Ok(lower_dirs) => layer_store::release(name, &lower_dirs),
+        // Never unpacked
+        Err(_) => Ok(()),
+    }
 }
 
 fn read_pid(pid_file_path: impl AsRef<std::path::Path>) -> Option<usize> {
@@ -80,21 +124,47 @@ fn write_pid(pid_file_path: impl AsRef<std::path::Path>) {
     let mut file = std::fs::File::options()
         .create(true)
         .write(true)
+        .truncate(true)
         .open(&pid_file_path)
         .unwrap();
     file.write_all(format!("{pid}").as_bytes()).unwrap();
 }
 
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Environment of the command run inside a container, usually taken from the image config
+#[derive(Debug, Default)]
+struct ProcessConfig {
+    /// `KEY=VALUE` pairs replacing the inherited environment if set
+    env: Option<Vec<String>>,
+    working_dir: Option<String>,
+    /// `user[:group]` as in the image config
+    user: Option<String>,
+}
+
 fn execute_command(
     command: impl AsRef<std::path::Path> + std::fmt::Debug,
     command_args: &[String],
     root: impl AsRef<std::path::Path>,
+    process: &ProcessConfig,
 ) -> anyhow::Result<()> {
     use anyhow::Context;
 
     // Chroot the root directory
     std::os::unix::fs::chroot(root).unwrap();
-    std::env::set_current_dir("/").unwrap();
+    let working_dir = process.working_dir.as_deref().unwrap_or("/");
+    std::fs::create_dir_all(working_dir).unwrap();
+    std::env::set_current_dir(working_dir).unwrap();
+
+    // Look up the user in the container's own databases
+    let user = match process.user.as_deref() {
+        Some(spec) if !spec.is_empty() => {
+            let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+            let group = std::fs::read_to_string("/etc/group").unwrap_or_default();
+            Some(user::resolve_user(spec, &passwd, &group)?)
+        }
+        _ => None,
+    };
 
     #[cfg(target_os = "linux")]
     {
@@ -118,20 +188,15 @@ fn execute_command(
         .stdin(std::process::Stdio::inherit())
         .stdout(std::process::Stdio::inherit())
         .stderr(std::process::Stdio::inherit());
+    if let Some(env) = &process.env {
+        command_exec.env_clear();
+        if !env.iter().any(|pair| pair.starts_with("PATH=")) {
+            command_exec.env("PATH", DEFAULT_PATH);
+        }
+        command_exec.envs(env.iter().filter_map(|pair| pair.split_once('=')));
+    }
     unsafe {
-        command_exec.pre_exec(move || {
-            #[cfg(target_os = "linux")]
-            {
-                // Make sure child gets killed if parent dies
-                let res = libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGHUP);
-                if res != 0 {
-                    return Err(nix::Error::from_i32(res).into());
-                }
-
-                mounting::mount_proc_in_container()?;
-            }
-            Ok(())
-        });
+        command_exec.pre_exec(move || prepare_child(user));
     }
     let child = command_exec.spawn().with_context(|| {
         format!(
@@ -156,6 +221,27 @@ fn execute_command(
     Ok(())
 }
 
+/// Runs in the child between `fork` and `exec`
+///
+/// `Command::uid` would switch to `user` before this runs, so the user is switched here instead,
+/// after everything that needs root.
+unsafe fn prepare_child(user: Option<user::UserIds>) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        // Make sure child gets killed if parent dies
+        let res = libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGHUP);
+        if res != 0 {
+            return Err(nix::Error::from_i32(res).into());
+        }
+
+        mounting::mount_proc_in_container()?;
+    }
+    if let Some(user) = user {
+        user::switch_user(user)?;
+    }
+    Ok(())
+}
+
 // https://stackoverflow.com/a/30540177/9920172
 struct ChildGuard(std::process::Child);
 
@@ -178,3 +264,39 @@ fn process_alive(pid: usize) -> bool {
     let res = unsafe { libc::kill(pid as i32, 0) };
     res == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_prepare_child_as_non_root_user() {
+        // Mounting `/proc` needs root in the first place
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "id -u; id -g; cat /proc/self/status > /dev/null"]);
+        unsafe {
+            command.pre_exec(|| {
+                // Keep the `/proc` mount away from the host
+                if libc::unshare(libc::CLONE_NEWNS) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let flags = nix::mount::MsFlags::MS_REC | nix::mount::MsFlags::MS_PRIVATE;
+                nix::mount::mount(None::<&str>, "/", None::<&str>, flags, None::<&str>)?;
+
+                let nobody = user::UserIds {
+                    uid: 65534,
+                    gid: 65534,
+                };
+                prepare_child(Some(nobody))
+            });
+        }
+        let output = command.output().unwrap();
+        assert!(output.status.success(), "{output:?}");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "65534\n65534\n");
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::BufRead;
+
+use anyhow::Context;
+use clap::Args;
+
+use crate::{
+    credentials::{config_file_path, normalize_domain, ConfigFile, Credentials},
+    image_reference::{registry_url, DOCKER_HUB_DOMAIN},
+    pull_image::DEFAULT_REGISTRY,
+    retry::RetryOptions,
+    token_auth::RegistryClient,
+};
+
+#[derive(Debug, Args)]
+pub struct LoginArgs {
+    /// Registry to log in to, Docker Hub if omitted
+    #[clap(default_value_t = String::from(DOCKER_HUB_DOMAIN))]
+    pub server: String,
+    #[clap(short, long)]
+    pub username: String,
+    /// Prefer `--password-stdin`, this ends up in the shell history
+    #[clap(short, long, conflicts_with = "password_stdin")]
+    pub password: Option<String>,
+    /// Read the password from the first line of stdin
+    #[clap(long, default_value_t = false)]
+    pub password_stdin: bool,
+    #[clap(short, long, default_value_t = String::from(DEFAULT_REGISTRY))]
+    pub registry: String,
+    #[clap(flatten)]
+    pub retry: RetryOptions,
+}
+
+impl LoginArgs {
+    // Usage: your_docker.sh login [server] --username <username> --password-stdin
+    pub fn run(self) -> anyhow::Result<()> {
+        let password = match self.password {
+            Some(password) => password,
+            None => {
+                let echo_off = match self.password_stdin {
+                    true => None,
+                    false => {
+                        if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+                            anyhow::bail!(
+                                "stdin is not a terminal, pass `--password-stdin` to read the password from it"
+                            );
+                        }
+                        eprint!("Password: ");
+                        Some(EchoOff::new()?)
+                    }
+                };
+                let mut password = String::new();
+                std::io::stdin().lock().read_line(&mut password)?;
+                if echo_off.is_some() {
+                    // The newline the user typed was not echoed either
+                    eprintln!();
+                }
+                password.trim_end_matches(['\r', '\n']).to_string()
+            }
+        };
+        let credentials = Credentials {
+            username: self.username,
+            password,
+        };
+        let domain = normalize_domain(&self.server);
+
+        // Only keep credentials the registry accepts
+        let url = format!("{}/v2/", registry_url(&domain, &self.registry));
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let client = RegistryClient::new(self.retry.clone(), Some(credentials.clone()));
+                let resp = client
+                    .send(|client| client.get(&url))
+                    .await
+                    .with_context(|| format!("login to `{domain}` failed"))?;
+                match resp.status() {
+                    reqwest::StatusCode::UNAUTHORIZED => {
+                        anyhow::bail!("login to `{domain}` failed: unauthorized")
+                    }
+                    _ => resp
+                        .error_for_status()
+                        .map(drop)
+                        .map_err(|e| anyhow::anyhow!("login to `{domain}` failed: {e}")),
+                }
+            })?;
+
+        let config_file_path = config_file_path()?;
+        let mut config = ConfigFile::load(&config_file_path)?;
+        config.insert(&domain, &credentials);
+        config.save(&config_file_path)?;
+        println!("Login Succeeded");
+        Ok(())
+    }
+}
+
+/// Keeps the terminal on stdin from echoing what is typed until dropped
+struct EchoOff(libc::termios);
+
+impl EchoOff {
+    fn new() -> std::io::Result<Self> {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut no_echo = termios;
+        no_echo.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &no_echo) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self(termios))
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
@@ -0,0 +1,30 @@
+use clap::Args;
+
+use crate::{
+    credentials::{config_file_path, normalize_domain, ConfigFile},
+    image_reference::DOCKER_HUB_DOMAIN,
+};
+
+#[derive(Debug, Args)]
+pub struct LogoutArgs {
+    /// Registry to log out from, Docker Hub if omitted
+    #[clap(default_value_t = String::from(DOCKER_HUB_DOMAIN))]
+    pub server: String,
+}
+
+impl LogoutArgs {
+    // Usage: your_docker.sh logout [server]
+    pub fn run(self) -> anyhow::Result<()> {
+        let domain = normalize_domain(&self.server);
+        let config_file_path = config_file_path()?;
+        let mut config = ConfigFile::load(&config_file_path)?;
+        if !config.remove(&domain) {
+            // Docker's own config file is only ever read
+            println!("Not logged in to {domain}");
+            return Ok(());
+        }
+        config.save(&config_file_path)?;
+        println!("Removing login credentials for {domain}");
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use clap::Args;
 
-use crate::{CONTAINERS, PACKED_LAYER_DIR};
+use crate::{image_store::ImageIndex, CONTAINERS};
 
 #[derive(Debug, Args)]
 pub struct LsArgs {}
@@ -15,23 +15,14 @@ impl LsArgs {
             println!("{}", container.file_name().to_str().unwrap());
         }
 
-        let layers = &PACKED_LAYER_DIR;
-        let mut images: Vec<String> = vec![];
-        let layers = std::fs::read_dir(layers.as_path()).unwrap();
-        for layer in layers {
-            let layer = layer.unwrap();
-            let layer = layer.file_name();
-            let mut split = layer.to_str().unwrap().split('.');
-            let image_left = split.next().unwrap().to_string();
-            let image_right = split.next().unwrap().to_string();
-            let image = format!("{image_left}/{image_right}");
-            if !images.contains(&image) {
-                images.push(image);
-            }
-        }
+        let index = ImageIndex::load()?;
         println!("Images:");
-        for image in images {
-            println!("{}", image);
+        for (name, reference, _) in index.iter() {
+            match reference.contains(':') {
+                // Pulled by digest
+                true => println!("{name}@{reference}"),
+                false => println!("{name}:{reference}"),
+            }
         }
 
         Ok(())
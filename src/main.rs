@@ -1,6 +1,10 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use docker_starter_rust::{exec::ExecArgs, ls::LsArgs, rm::RmArgs, rmi::RmiArgs, run::RunArgs};
+use docker_starter_rust::{
+    exec::ExecArgs, image::ImageArgs, images::ImagesArgs, login::LoginArgs, logout::LogoutArgs,
+    ls::LsArgs, pull::PullArgs, rm::RmArgs, rmi::RmiArgs, run::RunArgs, system::SystemArgs,
+    tag::TagArgs,
+};
 
 #[derive(Debug, Parser)]
 pub struct Cli {
@@ -11,19 +15,33 @@ pub struct Cli {
 #[derive(Debug, Subcommand)]
 enum Command {
     Run(RunArgs),
+    Pull(PullArgs),
     Exec(ExecArgs),
     Rm(RmArgs),
     Ls(LsArgs),
     Rmi(RmiArgs),
+    Images(ImagesArgs),
+    Tag(TagArgs),
+    Image(ImageArgs),
+    System(SystemArgs),
+    Login(LoginArgs),
+    Logout(LogoutArgs),
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
     match args.sub_command {
         Command::Run(run) => run.run(),
+        Command::Pull(pull) => pull.run(),
         Command::Exec(exec) => exec.run(),
         Command::Rm(rm) => rm.run(),
         Command::Ls(ls) => ls.run(),
         Command::Rmi(rmi) => rmi.run(),
+        Command::Images(images) => images.run(),
+        Command::Tag(tag) => tag.run(),
+        Command::Image(image) => image.run(),
+        Command::System(system) => system.run(),
+        Command::Login(login) => login.run(),
+        Command::Logout(logout) => logout.run(),
     }
 }
@@ -0,0 +1,150 @@
+//! Registry mirrors, e.g. pull-through caches of Docker Hub, kept in `~/.mydocker/mirrors.json`
+//!
+//! Mirrors are listed per registry domain and tried in order before the registry itself:
+//!
+//! ```json
+//! {
+//!     "docker.io": [
+//!         { "url": "https://mirror.example.com", "ca": "/etc/ssl/certs/example-ca.pem" },
+//!         { "url": "http://10.0.0.2:5000" },
+//!         { "url": "https://cache.local", "insecure": true }
+//!     ]
+//! }
+//! ```
+//!
+//! An `http://` URL speaks plain HTTP, `insecure` skips the verification of the mirror's certificate
+//! and `ca` trusts the PEM certificates of a private authority on top of the usual ones.
+
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::Context;
+use getset::{CopyGetters, Getters};
+use serde::Deserialize;
+
+use crate::credentials::{home_dir, normalize_domain};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MirrorsConfig(BTreeMap<String, Vec<Mirror>>);
+
+#[derive(Debug, Clone, Deserialize, Getters, CopyGetters)]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    #[getset(get = "pub")]
+    url: String,
+    #[serde(default)]
+    #[getset(get_copy = "pub")]
+    insecure: bool,
+    #[getset(get = "pub")]
+    ca: Option<PathBuf>,
+}
+
+impl MirrorsConfig {
+    /// The mirrors of `~/.mydocker/mirrors.json`, none if there is no such file
+    pub fn load_default() -> anyhow::Result<Self> {
+        match config_file_path() {
+            Some(path) => Self::load(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Self> {
+        let config: Self = match std::fs::read(path) {
+            Ok(config) => serde_json::from_slice(&config)
+                .with_context(|| format!("malformed mirrors file `{}`", path.display()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        for mirror in config.0.values().flatten() {
+            if !mirror.url.starts_with("https://") && !mirror.url.starts_with("http://") {
+                anyhow::bail!(
+                    "mirror `{}` in `{}` is not an HTTP(S) URL",
+                    mirror.url,
+                    path.display()
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    /// Mirrors of the registry at `domain`, in the order to try them
+    pub fn mirrors(&self, domain: &str) -> &[Mirror] {
+        let domain = normalize_domain(domain);
+        self.0
+            .iter()
+            .find(|(key, _)| normalize_domain(key) == domain)
+            .map(|(_, mirrors)| mirrors.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+impl Mirror {
+    /// Where the mirror serves the registry API
+    pub fn registry_base(&self) -> String {
+        format!("{}/v2", self.url.trim_end_matches('/'))
+    }
+
+    /// Domain the credentials of the mirror are stored under
+    pub fn domain(&self) -> String {
+        normalize_domain(&self.url)
+    }
+
+    pub fn http_client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder().danger_accept_invalid_certs(self.insecure);
+        if let Some(ca) = &self.ca {
+            let pem = std::fs::read(ca)
+                .with_context(|| format!("CA of mirror `{}`: `{}`", self.url, ca.display()))?;
+            // rustls reads every certificate of the bundle
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// `~/.mydocker/mirrors.json`, `None` without `HOME`
+pub fn config_file_path() -> Option<PathBuf> {
+    Some(home_dir()?.join(".mydocker").join("mirrors.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mirrors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mirrors.json");
+
+        assert!(MirrorsConfig::load(&path)
+            .unwrap()
+            .mirrors("docker.io")
+            .is_empty());
+
+        std::fs::write(
+            &path,
+            r#"{
+                "https://index.docker.io/v1/": [
+                    { "url": "https://mirror.example.com/", "ca": "/etc/ca.pem" },
+                    { "url": "http://10.0.0.2:5000", "insecure": true }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let config = MirrorsConfig::load(&path).unwrap();
+        let [first, second] = config.mirrors("registry-1.docker.io") else {
+            panic!("{config:?}");
+        };
+        assert_eq!(first.registry_base(), "https://mirror.example.com/v2");
+        assert_eq!(first.domain(), "mirror.example.com");
+        assert!(!first.insecure());
+        assert_eq!(second.domain(), "10.0.0.2:5000");
+        assert!(second.insecure());
+        assert!(config.mirrors("ghcr.io").is_empty());
+
+        std::fs::write(
+            &path,
+            r#"{ "docker.io": [{ "url": "mirror.example.com" }] }"#,
+        )
+        .unwrap();
+        assert!(MirrorsConfig::load(&path).is_err());
+    }
+}
@@ -1,12 +1,13 @@
-use crate::overlay_fs_lower_dir;
+use crate::read_lower_dirs;
 
-pub fn mount_root_fs(container_name: &str) {
+pub fn mount_root_fs(container_name: &str) -> anyhow::Result<()> {
     if mount_layers(container_name).is_err() {
         // We have to mount tmpfs inside a container
         // But the writable layers will not survive reboots
         mount_writable_tmp_fs(container_name);
-        mount_layers(container_name).unwrap();
+        mount_layers(container_name)?;
     }
+    Ok(())
 }
 
 pub fn mount_proc_in_container() -> std::io::Result<()> {
@@ -58,40 +59,50 @@ fn mount_writable_tmp_fs(container_name: &str) {
     .unwrap();
 }
 
-fn mount_layers(container_name: &str) -> nix::Result<()> {
-    use crate::{overlay_fs_upper_dir, overlay_fs_work_dir, root_fs_path};
+fn mount_layers(container_name: &str) -> anyhow::Result<()> {
+    use crate::{
+        overlay_fs_lower_links_dir, overlay_fs_upper_dir, overlay_fs_work_dir, root_fs_path,
+    };
 
-    let mut lower_dir_string = String::new();
-    for (i, layer) in overlay_fs_lower_dir(container_name)
-        .read_dir()
-        .unwrap()
-        .enumerate()
-    {
-        let layer = layer.unwrap();
-        if i != 0 {
-            lower_dir_string.push(':');
-        }
-        lower_dir_string.push_str(layer.path().to_str().unwrap());
+    // The mount options must fit in a page, which the store paths of a few dozen layers overflow.
+    // Link each lower dir by its position and hand overlayfs those names relative to the links dir.
+    let lower_dirs = read_lower_dirs(container_name)?;
+    let links_dir = overlay_fs_lower_links_dir(container_name);
+    let _ = std::fs::remove_dir_all(&links_dir);
+    std::fs::create_dir_all(&links_dir)?;
+    for (i, layer) in lower_dirs.iter().enumerate() {
+        std::os::unix::fs::symlink(layer, links_dir.join(i.to_string()))?;
     }
+    // overlayfs wants the top layer first
+    let lower_dir_string = (0..lower_dirs.len())
+        .rev()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(":");
 
     let upper_dir = overlay_fs_upper_dir(container_name);
-    std::fs::create_dir_all(&upper_dir).unwrap();
+    std::fs::create_dir_all(&upper_dir)?;
     let upper_dir = upper_dir.to_str().unwrap();
     // https://unix.stackexchange.com/a/330166
     let work_dir = overlay_fs_work_dir(container_name);
-    std::fs::create_dir_all(&work_dir).unwrap();
+    std::fs::create_dir_all(&work_dir)?;
     let work_dir = work_dir.to_str().unwrap();
     let root_fs = root_fs_path(container_name);
-    std::fs::create_dir_all(&root_fs).unwrap();
+    std::fs::create_dir_all(&root_fs)?;
 
     let overlay_o = format!("lowerdir={lower_dir_string},upperdir={upper_dir},workdir={work_dir}",);
     // dbg!(&overlay_o);
     // dbg!(&root_fs);
-    nix::mount::mount(
+    let cwd = std::env::current_dir()?;
+    std::env::set_current_dir(&links_dir)?;
+    let mounted = nix::mount::mount(
         Some("overlay"),
         &root_fs,
         Some("overlay"),
         nix::mount::MsFlags::empty(),
         Some(overlay_o.as_str()),
-    )
+    );
+    std::env::set_current_dir(cwd)?;
+    mounted?;
+    Ok(())
 }
@@ -0,0 +1,170 @@
+// https://github.com/opencontainers/image-spec/blob/main/image-index.md#platform-variants
+
+use std::{fmt, str::FromStr};
+
+use getset::Getters;
+
+/// `os/architecture[/variant]` an image is built for, e.g. `linux/arm64/v8`
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct Platform {
+    #[getset(get = "pub")]
+    os: String,
+    /// GOARCH style architecture, e.g. `amd64` rather than `x86_64`
+    #[getset(get = "pub")]
+    architecture: String,
+    #[getset(get = "pub")]
+    variant: Option<String>,
+}
+
+impl Platform {
+    /// Normalize the common aliases of architectures and variants
+    pub fn new(os: &str, architecture: &str, variant: Option<&str>) -> Self {
+        let os = os.to_lowercase();
+        let architecture = architecture.to_lowercase();
+        let variant = variant.map(str::to_lowercase);
+        let (architecture, variant) = match (architecture.as_str(), variant) {
+            ("x86_64" | "x86-64", variant) => ("amd64", variant),
+            ("i386" | "i686" | "x86", variant) => ("386", variant),
+            ("aarch64", variant) => ("arm64", variant),
+            ("armhf", None) => ("arm", Some("v7".to_string())),
+            ("armel", None) => ("arm", Some("v6".to_string())),
+            (_, variant) => (architecture.as_str(), variant),
+        };
+        Self {
+            os,
+            architecture: architecture.to_string(),
+            variant,
+        }
+    }
+
+    /// The platform of the machine we run on
+    ///
+    /// Containers are always Linux ones, whatever the host OS.
+    pub fn host() -> Self {
+        Self::new("linux", std::env::consts::ARCH, None)
+    }
+
+    /// How well an image built for `candidate` suits this platform, lower is better
+    ///
+    /// OS and architecture must be the same.
+    /// A missing variant stands for the baseline of the architecture, and an older variant of the same
+    /// architecture still runs, e.g. `arm/v6` on `arm/v7`.
+    pub fn rank(&self, candidate: &Platform) -> Option<u32> {
+        if self.os != candidate.os || self.architecture != candidate.architecture {
+            return None;
+        }
+        let wanted = self.effective_variant();
+        let offered = candidate.effective_variant();
+        if wanted == offered {
+            return Some(0);
+        }
+        match (version(wanted?)?, version(offered?)?) {
+            (wanted, offered) if offered < wanted => Some(wanted - offered),
+            _ => None,
+        }
+    }
+
+    fn effective_variant(&self) -> Option<&str> {
+        self.variant
+            .as_deref()
+            .or(match self.architecture.as_str() {
+                "arm64" => Some("v8"),
+                "arm" => Some("v7"),
+                "amd64" => Some("v1"),
+                _ => None,
+            })
+    }
+}
+
+/// `v7` → `7`
+fn version(variant: &str) -> Option<u32> {
+    variant.strip_prefix('v')?.parse().ok()
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{variant}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = PlatformError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.iter().any(|part| part.is_empty()) {
+            return Err(PlatformError(s.to_string()));
+        }
+        match parts.as_slice() {
+            [os, architecture] => Ok(Self::new(os, architecture, None)),
+            [os, architecture, variant] => Ok(Self::new(os, architecture, Some(variant))),
+            _ => Err(PlatformError(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid platform `{0}`, expected `os/architecture[/variant]`")]
+pub struct PlatformError(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let platform: Platform = "linux/arm64/v8".parse().unwrap();
+        assert_eq!(platform.os(), "linux");
+        assert_eq!(platform.architecture(), "arm64");
+        assert_eq!(platform.variant().as_deref(), Some("v8"));
+        assert_eq!(
+            "Linux/x86_64".parse::<Platform>().unwrap().to_string(),
+            "linux/amd64"
+        );
+        assert_eq!(
+            "linux/armhf".parse::<Platform>().unwrap().to_string(),
+            "linux/arm/v7"
+        );
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux//v7".parse::<Platform>().is_err());
+        assert!("linux/arm/v7/extra".parse::<Platform>().is_err());
+    }
+
+    #[test]
+    fn test_rank() {
+        let arm64 = Platform::new("linux", "arm64", None);
+        assert_eq!(
+            arm64.rank(&Platform::new("linux", "arm64", Some("v8"))),
+            Some(0)
+        );
+        assert_eq!(arm64.rank(&Platform::new("linux", "arm64", None)), Some(0));
+        assert_eq!(arm64.rank(&Platform::new("linux", "amd64", None)), None);
+        assert_eq!(arm64.rank(&Platform::new("windows", "arm64", None)), None);
+
+        let arm_v7 = Platform::new("linux", "arm", Some("v7"));
+        assert_eq!(arm_v7.rank(&Platform::new("linux", "arm", None)), Some(0));
+        assert_eq!(
+            arm_v7.rank(&Platform::new("linux", "arm", Some("v6"))),
+            Some(1)
+        );
+        assert_eq!(
+            arm_v7.rank(&Platform::new("linux", "arm", Some("v5"))),
+            Some(2)
+        );
+        let arm_v6 = Platform::new("linux", "arm", Some("v6"));
+        assert_eq!(
+            arm_v6.rank(&Platform::new("linux", "arm", Some("v7"))),
+            None
+        );
+
+        let amd64 = Platform::new("linux", "amd64", None);
+        assert_eq!(
+            amd64.rank(&Platform::new("linux", "amd64", Some("v2"))),
+            None
+        );
+    }
+}
@@ -0,0 +1,26 @@
+use clap::Args;
+
+use crate::{
+    image_reference::ImageReference,
+    pull_image::{pull, PullOptions, DEFAULT_REGISTRY},
+};
+
+#[derive(Debug, Args)]
+pub struct PullArgs {
+    pub image: ImageReference,
+    #[clap(short, long, default_value_t = String::from(DEFAULT_REGISTRY))]
+    pub registry: String,
+    #[clap(flatten)]
+    pub pull_options: PullOptions,
+}
+
+impl PullArgs {
+    // Usage: your_docker.sh pull <image>
+    pub fn run(self) -> anyhow::Result<()> {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async move { pull(&self.registry, &self.image, &self.pull_options).await })
+    }
+}
@@ -1,159 +1,594 @@
-use std::borrow::Cow;
+use std::{collections::HashSet, sync::Arc};
 
-use async_compression::tokio::bufread::GzipDecoder;
-use tokio::io::AsyncWriteExt;
+use anyhow::Context;
+use async_compression::tokio::bufread::{GzipDecoder, ZstdDecoder};
+use clap::Args;
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncWriteExt},
+    sync::Semaphore,
+};
 
-use crate::{overlay_fs_lower_dir, token_auth::pass_token_auth, PACKED_LAYER_DIR};
+use crate::{
+    credentials,
+    digest::{sha256_digest, verify_bytes, verify_file, DigestVerifier},
+    image_reference::ImageReference,
+    image_store::{blob_path, image_config, image_manifest, lock_blob, lock_pull, ImageIndex},
+    layer_store,
+    mirrors::MirrorsConfig,
+    platform::Platform,
+    retry::{is_transient_error, RetryOptions},
+    token_auth::RegistryClient,
+    whiteout::{unpack_layer, WhiteoutMode},
+    write_lower_dirs,
+};
+
+pub const DEFAULT_REGISTRY: &str = "https://registry.hub.docker.com";
 
 const MEDIA_TYPE_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const MEDIA_TYPE_OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
 const MEDIA_TYPE_DISTRIBUTION: &str = "application/vnd.docker.distribution.manifest.v2+json";
 const MEDIA_TYPE_OCI: &str = "application/vnd.oci.image.manifest.v1+json";
 
-pub async fn pull(registry: &str, image: &str, container_name: &str) {
-    let registry_base = format!("{registry}/v2");
-    let (image_name, image_version) = image.split_once(':').unwrap();
-    let image_name: Cow<'_, str> = match image_name.contains('/') {
-        true => image_name.into(),
-        false => format!("library/{}", image_name).into(),
-    };
-    // https://distribution.github.io/distribution/spec/api/#pulling-an-image-manifest
-    let url_manifests = format!("{registry_base}/{image_name}/manifests/{image_version}");
-
-    // https://distribution.github.io/distribution/spec/manifest-v2-2/#manifest-list
-    let resp = pass_token_auth(|client| {
-        client
-            .get(&url_manifests)
-            .header("Accept", MEDIA_TYPE_MANIFEST_LIST)
-    })
-    .await;
-    // dbg!(&resp);
-    // dbg!(&resp.text().await.unwrap());
-    let resp: serde_json::Value = resp.json().await.unwrap();
-    let manifest_list: models::ImageManifestList = serde_json::from_value(resp.clone()).unwrap();
-    if manifest_list.schema_version() != 2 {
-        panic!(
-            "Manifest list schema version `{}` not supported",
-            manifest_list.schema_version()
-        );
+const DEFAULT_MAX_CONCURRENT_DOWNLOADS: u16 = 3;
+
+#[derive(Debug, Clone, Args)]
+pub struct PullOptions {
+    /// Maximum number of layers downloaded at the same time
+    #[clap(long, default_value_t = DEFAULT_MAX_CONCURRENT_DOWNLOADS, value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_concurrent_downloads: u16,
+    /// Platform to pull as `os/architecture[/variant]`, e.g. `linux/arm64/v8`, defaulting to this machine's
+    #[clap(long)]
+    pub platform: Option<Platform>,
+    #[clap(flatten)]
+    pub retry: RetryOptions,
+}
+
+impl Default for PullOptions {
+    fn default() -> Self {
+        Self {
+            max_concurrent_downloads: DEFAULT_MAX_CONCURRENT_DOWNLOADS,
+            platform: None,
+            retry: RetryOptions::default(),
+        }
     }
-    let manifest_list: models::ImageManifestListV2 = serde_json::from_value(resp).unwrap();
-    // dbg!(&manifest_list);
-    let manifest = &manifest_list
-        .manifests()
-        .iter()
-        .find(|manifest| manifest.platform().architecture() == docker_arch())
-        .unwrap();
-    let (media_type, digest) = (manifest.media_type(), manifest.digest());
+}
 
-    match media_type.as_str() {
-        MEDIA_TYPE_DISTRIBUTION => {
-            // https://distribution.github.io/distribution/spec/manifest-v2-2/#image-manifest
-            handle_manifest(
-                &registry_base,
-                &image_name,
-                digest,
-                MEDIA_TYPE_DISTRIBUTION,
-                container_name,
+/// Fetch the manifest, config and layers of `image` into the image store
+///
+/// The mirrors of the registry are tried in order before the registry itself.
+/// `default_registry` serves the images that live on Docker Hub.
+pub async fn pull(
+    default_registry: &str,
+    image: &ImageReference,
+    options: &PullOptions,
+) -> anyhow::Result<()> {
+    // Keep garbage collection off the blobs until the index references them
+    let _lock = lock_pull().await?;
+    let scope = format!("repository:{}:pull", image.repository());
+
+    let mirrors = MirrorsConfig::load_default()?;
+    for mirror in mirrors.mirrors(image.domain()) {
+        let pulled = async {
+            // Mirrors get their own credentials, never those of the registry they mirror
+            let client = RegistryClient::with_http_client(
+                mirror.http_client()?,
+                options.retry.clone(),
+                credentials::lookup(&mirror.domain())?,
             )
-            .await
+            .with_default_scope(scope.clone());
+            pull_from(&client, &mirror.registry_base(), image, options).await
+        };
+        match pulled.await {
+            Ok(()) => return Ok(()),
+            Err(e) => eprintln!(
+                "Failed to pull `{image}` from mirror `{}`: {e:#}",
+                mirror.url()
+            ),
+        }
+    }
+
+    let registry_base = format!("{}/v2", image.registry_url(default_registry));
+    let client = RegistryClient::new(options.retry.clone(), credentials::lookup(image.domain())?)
+        .with_default_scope(scope);
+    pull_from(&client, &registry_base, image, options).await
+}
+
+async fn pull_from(
+    client: &RegistryClient,
+    registry_base: &str,
+    image: &ImageReference,
+    options: &PullOptions,
+) -> anyhow::Result<()> {
+    // Let the registry pick whichever of the supported formats it has the image in
+    let accept = [
+        MEDIA_TYPE_MANIFEST_LIST,
+        MEDIA_TYPE_OCI_INDEX,
+        MEDIA_TYPE_DISTRIBUTION,
+        MEDIA_TYPE_OCI,
+    ]
+    .join(", ");
+    let (media_type, manifest_bytes) =
+        fetch_manifest(client, registry_base, image, image.reference(), &accept).await?;
+    if let Some(digest) = image.digest() {
+        verify_bytes(digest, None, &manifest_bytes)
+            .map_err(|e| anyhow::anyhow!("manifest of `{image}`: {e}"))?;
+    }
+
+    match media_type.as_str() {
+        // https://distribution.github.io/distribution/spec/manifest-v2-2/#manifest-list
+        // https://github.com/opencontainers/image-spec/blob/main/image-index.md
+        MEDIA_TYPE_MANIFEST_LIST | MEDIA_TYPE_OCI_INDEX => {
+            let manifest_list: models::ImageManifestList = serde_json::from_slice(&manifest_bytes)?;
+            if manifest_list.schema_version() != 2 {
+                anyhow::bail!(
+                    "manifest list schema version `{}` not supported",
+                    manifest_list.schema_version()
+                );
+            }
+            let manifest_list: models::ImageManifestListV2 =
+                serde_json::from_slice(&manifest_bytes)?;
+            let platform = options.platform.clone().unwrap_or_else(Platform::host);
+            let manifest = select_manifest(manifest_list.manifests(), &platform)
+                .with_context(|| format!("cannot pull `{image}`"))?;
+            let digest = manifest.digest();
+
+            let (media_type, manifest_bytes) =
+                fetch_manifest(client, registry_base, image, digest, manifest.media_type()).await?;
+            verify_bytes(digest, Some(manifest.size()), &manifest_bytes)
+                .map_err(|e| anyhow::anyhow!("manifest `{digest}`: {e}"))?;
+            match media_type.as_str() {
+                MEDIA_TYPE_DISTRIBUTION | MEDIA_TYPE_OCI => {
+                    handle_manifest(
+                        client,
+                        registry_base,
+                        image,
+                        digest,
+                        &manifest_bytes,
+                        options,
+                    )
+                    .await
+                }
+                _ => anyhow::bail!("unsupported media type `{media_type}` of manifest `{digest}`"),
+            }
         }
+        // https://distribution.github.io/distribution/spec/manifest-v2-2/#image-manifest
         // https://github.com/opencontainers/image-spec/blob/main/manifest.md
-        MEDIA_TYPE_OCI => {
+        MEDIA_TYPE_DISTRIBUTION | MEDIA_TYPE_OCI => {
+            // A single-platform image, stored under the digest it was pinned to or the one of its content
+            let digest = match image.digest() {
+                Some(digest) => digest.clone(),
+                None => sha256_digest(&manifest_bytes),
+            };
             handle_manifest(
-                &registry_base,
-                &image_name,
-                digest,
-                MEDIA_TYPE_OCI,
-                container_name,
+                client,
+                registry_base,
+                image,
+                &digest,
+                &manifest_bytes,
+                options,
             )
             .await
         }
-        _ => panic!("{media_type}"),
+        _ => anyhow::bail!("unsupported media type `{media_type}` of manifest `{image}`"),
     }
 }
 
-async fn handle_manifest(
+/// Fetch the manifest of `image` by tag or digest, returning its media type along with it
+// https://distribution.github.io/distribution/spec/api/#pulling-an-image-manifest
+async fn fetch_manifest(
+    client: &RegistryClient,
     registry_base: &str,
-    image_name: &str,
-    digest: &str,
+    image: &ImageReference,
+    reference: &str,
     accept: &str,
-    container_name: &str,
-) {
-    let url_manifest = format!("{registry_base}/{image_name}/manifests/{digest}");
-    // let url_manifest = format!("{registry_base}/library/{image_name}/manifests/{image_version}");
-    let resp = pass_token_auth(|client| client.get(&url_manifest).header("Accept", accept)).await;
-    // dbg!(&resp);
-    let manifest: models::ImageManifest = resp.json().await.unwrap();
-    // dbg!(&manifest);
-    // dbg!(&resp.text().await.unwrap());
+) -> anyhow::Result<(String, bytes::Bytes)> {
+    let url_manifest = format!(
+        "{registry_base}/{}/manifests/{reference}",
+        image.repository()
+    );
+    let resp = client
+        .send(|client| client.get(&url_manifest).header("Accept", accept))
+        .await?
+        .error_for_status()?;
+    let content_type = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        });
+    let manifest_bytes = resp.bytes().await?;
+
+    // Some registries serve manifests as plain JSON, in which case the manifest tells its own type
+    let media_type = match content_type {
+        Some(content_type) if content_type.starts_with("application/vnd.") => content_type,
+        _ => {
+            let manifest: serde_json::Value = serde_json::from_slice(&manifest_bytes)
+                .with_context(|| format!("manifest of `{image}` is not JSON"))?;
+            match manifest.get("mediaType").and_then(|t| t.as_str()) {
+                Some(media_type) => media_type.to_string(),
+                // `mediaType` is optional in OCI, so go by the shape
+                None if manifest.get("manifests").is_some() => MEDIA_TYPE_OCI_INDEX.to_string(),
+                None if manifest.get("config").is_some() => MEDIA_TYPE_OCI.to_string(),
+                None => anyhow::bail!("cannot tell the media type of manifest `{image}`"),
+            }
+        }
+    };
+    Ok((media_type, manifest_bytes))
+}
+
+/// Stack the layers of an already pulled `image` as the lower dirs of the container
+///
+/// Layers are unpacked once into the shared layer store and referenced by every container using them.
+pub async fn unpack(image: &ImageReference, container_name: &str) -> anyhow::Result<()> {
+    let manifest = image_manifest(image)?;
+    let config = image_config(image)?;
+    let diff_ids = config.rootfs().diff_ids();
+    if diff_ids.len() != manifest.layers().len() {
+        anyhow::bail!(
+            "image `{image}` has {} layers but {} diff IDs",
+            manifest.layers().len(),
+            diff_ids.len()
+        );
+    }
 
-    let unpack_layer_dir = overlay_fs_lower_dir(container_name);
-    for (i, layer) in manifest.layers().iter().enumerate() {
-        let unpack_dir = unpack_layer_dir.join(format!("layer.{i}"));
+    let mut lower_dirs = vec![];
+    for (layer, diff_id) in manifest.layers().iter().zip(diff_ids) {
+        let lower_dir = layer_store::acquire(diff_id, container_name, |unpack_dir| async move {
+            let tar = open_layer(layer).await?;
+            unpack_layer(tar, &unpack_dir, WhiteoutMode::Overlay).await
+        })
+        .await;
+        match lower_dir {
+            Ok(lower_dir) => lower_dirs.push(lower_dir),
+            Err(e) => {
+                // Do not keep the layers unpacked so far from being freed
+                layer_store::release(container_name, &lower_dirs)?;
+                return Err(e);
+            }
+        }
+    }
+
+    // The stacking order is recorded rather than inferred from the directory names
+    write_lower_dirs(container_name, &lower_dirs)
+}
+
+/// Unpack all layers of an already pulled `image` on top of each other into `dst`
+///
+/// This is for when the layers cannot be stacked with overlayfs.
+pub async fn unpack_flat(image: &ImageReference, dst: &std::path::Path) -> anyhow::Result<()> {
+    let manifest = image_manifest(image)?;
+    for layer in manifest.layers() {
+        let tar = open_layer(layer).await?;
+        unpack_layer(tar, dst, WhiteoutMode::Apply).await?;
+    }
+    Ok(())
+}
+
+async fn open_layer(
+    layer: &models::ImageLayer,
+) -> anyhow::Result<Box<dyn AsyncRead + Unpin + Send>> {
+    let compression = LayerCompression::from_media_type(layer.media_type())
+        .with_context(|| format!("cannot unpack layer `{}`", layer.digest()))?;
+    let file_path = blob_path(layer.digest())?;
+    let file = tokio::fs::File::options()
+        .read(true)
+        .open(&file_path)
+        .await
+        .with_context(|| format!("failed to open layer `{}`", file_path.display()))?;
+    Ok(compression.decoder(tokio::io::BufReader::new(file)))
+}
+
+// https://github.com/opencontainers/image-spec/blob/main/layer.md#distributable-format
+// https://distribution.github.io/distribution/spec/manifest-v2-2/#image-manifest-field-descriptions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LayerCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl LayerCompression {
+    fn from_media_type(media_type: &str) -> anyhow::Result<Self> {
+        match media_type {
+            "application/vnd.oci.image.layer.v1.tar"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar"
+            | "application/vnd.docker.image.rootfs.diff.tar" => Ok(Self::None),
+            "application/vnd.oci.image.layer.v1.tar+gzip"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip"
+            | "application/vnd.docker.image.rootfs.diff.tar.gzip"
+            | "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip" => Ok(Self::Gzip),
+            "application/vnd.oci.image.layer.v1.tar+zstd"
+            | "application/vnd.oci.image.layer.nondistributable.v1.tar+zstd" => Ok(Self::Zstd),
+            _ => anyhow::bail!("unsupported layer media type `{media_type}`"),
+        }
+    }
+
+    fn decoder<R>(self, reader: R) -> Box<dyn AsyncRead + Unpin + Send>
+    where
+        R: AsyncBufRead + Unpin + Send + 'static,
+    {
+        match self {
+            Self::None => Box::new(reader),
+            Self::Gzip => Box::new(GzipDecoder::new(reader)),
+            Self::Zstd => Box::new(ZstdDecoder::new(reader)),
+        }
+    }
+}
 
-        let digest = layer.digest();
+/// Fetch the config and layers of the image manifest `manifest_bytes`, then store it and tag `image` with it
+async fn handle_manifest(
+    client: &RegistryClient,
+    registry_base: &str,
+    image: &ImageReference,
+    digest: &str,
+    manifest_bytes: &[u8],
+    options: &PullOptions,
+) -> anyhow::Result<()> {
+    let image_name = image.repository();
+    let manifest: models::ImageManifest = serde_json::from_slice(manifest_bytes)?;
+    // Refuse layers we could not unpack before downloading anything
+    for layer in manifest.layers() {
+        LayerCompression::from_media_type(layer.media_type())
+            .with_context(|| format!("cannot pull layer `{}`", layer.digest()))?;
+    }
+
+    // https://distribution.github.io/distribution/spec/manifest-v2-2/#image-manifest-field-descriptions
+    let config = manifest.config();
+    pull_blob(
+        client,
+        registry_base,
+        image_name,
+        config.digest(),
+        config.size(),
+        &[],
+        None,
+    )
+    .await?;
 
-        let _ = tokio::fs::remove_dir_all(&unpack_dir).await;
-        tokio::fs::create_dir_all(&unpack_dir).await.unwrap();
+    // Foreign layers come from elsewhere than the registry, through a client of their own shared by all of them
+    let foreign_client = manifest
+        .layers()
+        .iter()
+        .any(|layer| layer.urls().as_ref().is_some_and(|urls| !urls.is_empty()))
+        .then(|| RegistryClient::new(client.retry().clone(), None));
 
-        let file_path = pull_layer(registry_base, image_name, i, digest).await;
-        let tar_gz = tokio::fs::File::options()
-            .read(true)
-            .open(file_path)
+    // Download layers concurrently, at most `max_concurrent_downloads` at a time.
+    // A layer may come several times, e.g. the empty one, but is only downloaded once.
+    let semaphore = Arc::new(Semaphore::new(options.max_concurrent_downloads.into()));
+    let mut downloads = tokio::task::JoinSet::new();
+    let mut seen = HashSet::new();
+    let layers = manifest.layers().iter();
+    for layer in layers.filter(|layer| seen.insert(layer.digest())) {
+        let registry_base = registry_base.to_string();
+        let image_name = image_name.to_string();
+        let layer = layer.clone();
+        let semaphore = Arc::clone(&semaphore);
+        let client = client.clone();
+        let foreign_client = foreign_client.clone();
+        downloads.spawn(async move {
+            let _permit = semaphore.acquire_owned().await.unwrap();
+            pull_blob(
+                &client,
+                &registry_base,
+                &image_name,
+                layer.digest(),
+                layer.size(),
+                layer.urls().as_deref().unwrap_or_default(),
+                foreign_client.as_ref(),
+            )
             .await
-            .unwrap();
-        let tar_gz = tokio::io::BufReader::new(tar_gz);
-        let tar = GzipDecoder::new(tar_gz);
-        let mut archive = tokio_tar::Archive::new(tar);
-        archive.unpack(&unpack_dir).await.unwrap();
+        });
     }
+    while let Some(download) = downloads.join_next().await {
+        if let Err(e) = download
+            .map_err(anyhow::Error::from)
+            .and_then(|pulled| pulled)
+        {
+            // Stop the other downloads rather than leave them holding their blobs behind our back
+            downloads.shutdown().await;
+            return Err(e);
+        }
+    }
+
+    // Only store and tag the manifest once everything it references is in the store
+    let manifest_path = blob_path(digest)?;
+    tokio::fs::create_dir_all(manifest_path.parent().unwrap()).await?;
+    tokio::fs::write(&manifest_path, &manifest_bytes).await?;
+    ImageIndex::update(|index| index.insert(image, digest))?;
+    Ok(())
 }
 
 // https://distribution.github.io/distribution/spec/api/#pulling-a-layer
-async fn pull_layer(
+async fn pull_blob(
+    client: &RegistryClient,
     registry_base: &str,
     image_name: &str,
-    layer_index: usize,
     digest: &str,
-) -> std::path::PathBuf {
-    tokio::fs::create_dir_all(PACKED_LAYER_DIR.as_path())
-        .await
-        .unwrap();
-    let (image_name_left, image_name_right) = image_name.split_once('/').unwrap();
-    let file_path = PACKED_LAYER_DIR.join(format!(
-        "{image_name_left}.{image_name_right}.{layer_index}.{digest}.tar.gz"
-    ));
+    size: usize,
+    urls: &[String],
+    foreign_client: Option<&RegistryClient>,
+) -> anyhow::Result<std::path::PathBuf> {
+    let file_path = blob_path(digest)?;
+    tokio::fs::create_dir_all(file_path.parent().unwrap()).await?;
+    // Another pull may be downloading the same blob: wait for it to finish and reuse its download
+    // rather than write to the same `.partial` file
+    let _lock = lock_blob(digest).await?;
     if file_path.exists() {
-        // Use cached layer
-        return file_path;
+        // Use cached blob unless it has been corrupted
+        match verify_file(digest, Some(size), &file_path).await {
+            Ok(()) => return Ok(file_path),
+            Err(_) => tokio::fs::remove_file(&file_path)
+                .await
+                .with_context(|| format!("failed to remove corrupted blob `{digest}`"))?,
+        }
+    }
+
+    // Foreign layers may only be served from the URLs of their descriptor, so try those first.
+    // Those are not the registry, which is why `foreign_client` has neither credentials nor tokens.
+    // https://github.com/opencontainers/image-spec/blob/main/descriptor.md#properties
+    if let Some(foreign_client) = foreign_client {
+        for url in urls {
+            if !(url.starts_with("https://") || url.starts_with("http://")) {
+                eprintln!("Skipping URL `{url}` of blob `{digest}`: not HTTP(S)");
+                continue;
+            }
+            match fetch_blob_resuming(foreign_client, url, &file_path, digest, size).await {
+                Ok(()) => return Ok(file_path),
+                Err(e) => eprintln!("Failed to download blob `{digest}` from `{url}`: {e}"),
+            }
+        }
     }
 
     let url_blob = format!("{registry_base}/{image_name}/blobs/{digest}");
     // dbg!(&url_blob);
-    let resp = pass_token_auth(|client| client.get(&url_blob)).await;
+    fetch_blob_resuming(client, &url_blob, &file_path, digest, size).await?;
+    Ok(file_path)
+}
+
+/// A connection dropped mid-body is retried, picking up from the `.partial` file
+async fn fetch_blob_resuming(
+    client: &RegistryClient,
+    url_blob: &str,
+    file_path: &std::path::Path,
+    digest: &str,
+    size: usize,
+) -> anyhow::Result<()> {
+    let retry = client.retry();
+    let mut attempt = 0;
+    loop {
+        match fetch_blob(client, url_blob, file_path, digest, size).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < retry.max_retries && is_interrupted(&e) => {
+                tokio::time::sleep(retry.backoff(attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_interrupted(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .map(is_transient_error)
+        .unwrap_or(false)
+}
+
+async fn fetch_blob(
+    client: &RegistryClient,
+    url_blob: &str,
+    file_path: &std::path::Path,
+    digest: &str,
+    size: usize,
+) -> anyhow::Result<()> {
+    // Resume an interrupted download if the registry supports ranges
+    let partial_path = partial_file_path(file_path);
+    let offset = match tokio::fs::metadata(&partial_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    let resp = client
+        .send(|client| {
+            let req = client.get(url_blob);
+            match offset {
+                0 => req,
+                _ => req.header(reqwest::header::RANGE, format!("bytes={offset}-")),
+            }
+        })
+        .await?;
     // dbg!(&resp);
+    let resp = match resp.status() {
+        // The partial file is no shorter than the blob so it cannot be resumed
+        reqwest::StatusCode::RANGE_NOT_SATISFIABLE => {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            client.send(|client| client.get(url_blob)).await?
+        }
+        _ => resp,
+    };
 
-    download(resp, &file_path).await;
-    file_path
+    download(resp.error_for_status()?, file_path, digest, size).await
 }
 
-async fn download(resp: reqwest::Response, file_path: impl AsRef<std::path::Path>) {
-    let bytes = resp.bytes().await.unwrap();
+/// Stream the response body to `file_path` and keep it only if it matches `digest` and `size`
+///
+/// The body goes to a `.partial` sibling file first which is renamed into place once verified,
+/// so `file_path` never exists half-written.
+/// A `206 Partial Content` response is appended to what the `.partial` file already holds.
+async fn download(
+    mut resp: reqwest::Response,
+    file_path: impl AsRef<std::path::Path>,
+    digest: &str,
+    size: usize,
+) -> anyhow::Result<()> {
+    let file_path = file_path.as_ref();
+    let partial_path = partial_file_path(file_path);
+    let mut verifier = DigestVerifier::new(digest, Some(size))?;
+
+    let resume = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    if resume {
+        verifier
+            .update_from_file(&partial_path)
+            .await
+            .with_context(|| format!("failed to read `{}`", partial_path.display()))?;
+        let offset = tokio::fs::metadata(&partial_path)
+            .await
+            .with_context(|| format!("failed to read `{}`", partial_path.display()))?
+            .len();
+        let content_range = resp
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        if !content_range.starts_with(&format!("bytes {offset}-")) {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            anyhow::bail!("blob `{digest}`: unexpected content range `{content_range}`");
+        }
+    }
 
     let mut file = tokio::fs::File::options()
         .create(true)
         .write(true)
-        .open(&file_path)
+        .append(resume)
+        .truncate(!resume)
+        .open(&partial_path)
         .await
-        .unwrap();
-    file.write_all(&bytes).await.unwrap();
+        .with_context(|| format!("failed to open `{}`", partial_path.display()))?;
+    let write_error = || format!("failed to write `{}`", partial_path.display());
+    while let Some(chunk) = resp.chunk().await? {
+        verifier.update(&chunk);
+        file.write_all(&chunk).await.with_context(write_error)?;
+    }
+    file.flush().await.with_context(write_error)?;
+    drop(file);
+
+    if let Err(e) = verifier.verify() {
+        let _ = tokio::fs::remove_file(&partial_path).await;
+        anyhow::bail!("blob `{digest}`: {e}");
+    }
+    tokio::fs::rename(&partial_path, file_path)
+        .await
+        .with_context(|| format!("failed to move blob `{digest}` into place"))?;
+    Ok(())
+}
+
+fn partial_file_path(file_path: &std::path::Path) -> std::path::PathBuf {
+    let mut file_name = file_path.file_name().unwrap().to_os_string();
+    file_name.push(".partial");
+    file_path.with_file_name(file_name)
 }
 
 #[allow(dead_code)]
-mod models {
+pub mod models {
     use getset::{CopyGetters, Getters};
     use serde::Deserialize;
 
+    use crate::platform::Platform;
+
     #[derive(Debug, Clone, Deserialize, Getters, CopyGetters)]
     #[serde(rename_all = "camelCase")]
     pub struct ImageManifestList {
@@ -166,8 +601,9 @@ mod models {
     pub struct ImageManifestListV2 {
         #[getset(get_copy = "pub")]
         schema_version: usize,
+        /// Optional in OCI image indexes
         #[getset(get = "pub")]
-        media_type: String,
+        media_type: Option<String>,
         #[getset(get = "pub")]
         manifests: Vec<ImagePlatformManifest>,
     }
@@ -181,8 +617,9 @@ mod models {
         size: usize,
         #[getset(get = "pub")]
         digest: String,
+        /// Optional in OCI image indexes, e.g. missing for artifacts
         #[getset(get = "pub")]
-        platform: ImagePlatform,
+        platform: Option<ImagePlatform>,
     }
 
     #[derive(Debug, Clone, Deserialize, Getters)]
@@ -204,13 +641,20 @@ mod models {
         features: Option<Vec<String>>,
     }
 
+    impl ImagePlatform {
+        pub fn to_platform(&self) -> Platform {
+            Platform::new(&self.os, &self.architecture, self.variant.as_deref())
+        }
+    }
+
     #[derive(Debug, Clone, Deserialize, Getters, CopyGetters)]
     #[serde(rename_all = "camelCase")]
     pub struct ImageManifest {
         #[getset(get_copy = "pub")]
         schema_version: usize,
+        /// Optional in OCI image manifests
         #[getset(get = "pub")]
-        media_type: String,
+        media_type: Option<String>,
         #[getset(get = "pub")]
         config: ImageConfig,
         #[getset(get = "pub")]
@@ -240,18 +684,76 @@ mod models {
         #[getset(get = "pub")]
         urls: Option<Vec<String>>,
     }
-}
 
-fn docker_arch() -> &'static str {
-    let arch = std::env::consts::ARCH;
-    match arch {
-        "x86" => "i386",
-        "x86_64" => "amd64",
-        "aarch64" => "arm64",
-        _ => arch,
+    // https://github.com/opencontainers/image-spec/blob/main/config.md
+    #[derive(Debug, Clone, Deserialize, Getters)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ImageConfigFile {
+        #[getset(get = "pub")]
+        created: Option<String>,
+        #[getset(get = "pub")]
+        architecture: String,
+        #[getset(get = "pub")]
+        os: String,
+        #[getset(get = "pub")]
+        config: Option<ContainerConfig>,
+        #[getset(get = "pub")]
+        rootfs: RootFs,
+    }
+
+    #[derive(Debug, Clone, Default, Deserialize, Getters)]
+    #[serde(rename_all = "PascalCase")]
+    pub struct ContainerConfig {
+        #[getset(get = "pub")]
+        user: Option<String>,
+        #[getset(get = "pub")]
+        env: Option<Vec<String>>,
+        #[getset(get = "pub")]
+        entrypoint: Option<Vec<String>>,
+        #[getset(get = "pub")]
+        cmd: Option<Vec<String>>,
+        #[getset(get = "pub")]
+        working_dir: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Getters)]
+    pub struct RootFs {
+        #[getset(get = "pub")]
+        #[serde(rename = "type")]
+        type_: String,
+        #[getset(get = "pub")]
+        diff_ids: Vec<String>,
     }
 }
 
+/// The manifest built for the platform closest to `platform`
+fn select_manifest<'a>(
+    manifests: &'a [models::ImagePlatformManifest],
+    platform: &Platform,
+) -> anyhow::Result<&'a models::ImagePlatformManifest> {
+    // Entries without a platform are not images to run
+    let platforms = manifests.iter().filter_map(|manifest| {
+        let candidate = manifest.platform().as_ref()?.to_platform();
+        Some((candidate, manifest))
+    });
+    platforms
+        .clone()
+        .filter_map(|(candidate, manifest)| Some((platform.rank(&candidate)?, manifest)))
+        .min_by_key(|(rank, _)| *rank)
+        .map(|(_, manifest)| manifest)
+        .ok_or_else(|| {
+            let available = platforms
+                .map(|(candidate, _)| candidate.to_string())
+                // Attestations are listed as `unknown/unknown`
+                .filter(|available| available != "unknown/unknown")
+                .collect::<Vec<_>>();
+            anyhow::anyhow!(
+                "no image for platform `{platform}`, available platforms: {}",
+                available.join(", ")
+            )
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
@@ -260,21 +762,104 @@ mod tests {
 
     use super::*;
 
-    const DEFAULT_REGISTRY: &str = "https://registry.hub.docker.com";
+    #[tokio::test]
+    async fn test_layer_compression() {
+        use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+        use tokio::io::AsyncReadExt;
+
+        async fn decode(media_type: &str, compressed: Vec<u8>) -> Vec<u8> {
+            let compression = LayerCompression::from_media_type(media_type).unwrap();
+            let mut decoded = vec![];
+            compression
+                .decoder(std::io::Cursor::new(compressed))
+                .read_to_end(&mut decoded)
+                .await
+                .unwrap();
+            decoded
+        }
+
+        let tar = b"not really a tarball".to_vec();
+        let mut gzip = vec![];
+        GzipEncoder::new(tar.as_slice())
+            .read_to_end(&mut gzip)
+            .await
+            .unwrap();
+        let mut zstd = vec![];
+        ZstdEncoder::new(tar.as_slice())
+            .read_to_end(&mut zstd)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            decode("application/vnd.docker.image.rootfs.diff.tar.gzip", gzip).await,
+            tar
+        );
+        assert_eq!(
+            decode("application/vnd.oci.image.layer.v1.tar+zstd", zstd).await,
+            tar
+        );
+        assert_eq!(
+            decode("application/vnd.oci.image.layer.v1.tar", tar.clone()).await,
+            tar
+        );
+        assert!(
+            LayerCompression::from_media_type("application/vnd.oci.image.layer.v1.tar+bzip2")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_select_manifest() {
+        let index: models::ImageManifestListV2 = serde_json::from_str(
+            r#"{
+                "schemaVersion": 2,
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "manifests": [
+                    { "mediaType": "application/vnd.oci.image.manifest.v1+json", "size": 1, "digest": "sha256:artifact" },
+                    {
+                        "mediaType": "application/vnd.oci.image.manifest.v1+json", "size": 1, "digest": "sha256:arm64",
+                        "platform": { "architecture": "arm64", "os": "linux" }
+                    },
+                    {
+                        "mediaType": "application/vnd.oci.image.manifest.v1+json", "size": 1, "digest": "sha256:amd64",
+                        "platform": { "architecture": "amd64", "os": "linux" }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let amd64 = Platform::new("linux", "amd64", None);
+        let manifest = select_manifest(index.manifests(), &amd64).unwrap();
+        assert_eq!(manifest.digest(), "sha256:amd64");
+
+        let err = select_manifest(index.manifests(), &Platform::new("linux", "riscv64", None))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.ends_with("available platforms: linux/arm64, linux/amd64"),
+            "{err}"
+        );
+    }
 
     #[tokio::test]
     #[serial]
     async fn test_pull_distribution() {
-        let image = "busybox:latest";
+        let image = "busybox:latest".parse().unwrap();
         let _ = tokio::fs::remove_dir_all(root_fs_path("test")).await;
-        pull(DEFAULT_REGISTRY, image, "test").await;
+        pull(DEFAULT_REGISTRY, &image, &PullOptions::default())
+            .await
+            .unwrap();
+        unpack(&image, "test").await.unwrap();
     }
 
     #[tokio::test]
     #[serial]
     async fn test_pull_oci() {
-        let image = "ubuntu:latest";
+        let image = "ubuntu:latest".parse().unwrap();
         let _ = tokio::fs::remove_dir_all(root_fs_path("test")).await;
-        pull(DEFAULT_REGISTRY, image, "test").await;
+        pull(DEFAULT_REGISTRY, &image, &PullOptions::default())
+            .await
+            .unwrap();
+        unpack(&image, "test").await.unwrap();
     }
 }
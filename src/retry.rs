@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use clap::Args;
+
+const DEFAULT_MAX_RETRIES: u32 = 5;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 500;
+const DEFAULT_MAX_RETRY_BACKOFF_MS: u64 = 30_000;
+
+#[derive(Debug, Clone, Args)]
+pub struct RetryOptions {
+    /// Number of times a failed registry request is retried
+    #[clap(long, default_value_t = DEFAULT_MAX_RETRIES)]
+    pub max_retries: u32,
+    /// Delay before the first retry in milliseconds, doubled on every further retry
+    #[clap(long, default_value_t = DEFAULT_RETRY_BACKOFF_MS)]
+    pub retry_backoff_ms: u64,
+    /// Upper bound of the delay between two retries in milliseconds
+    #[clap(long, default_value_t = DEFAULT_MAX_RETRY_BACKOFF_MS)]
+    pub max_retry_backoff_ms: u64,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_backoff_ms: DEFAULT_RETRY_BACKOFF_MS,
+            max_retry_backoff_ms: DEFAULT_MAX_RETRY_BACKOFF_MS,
+        }
+    }
+}
+
+impl RetryOptions {
+    /// Exponential backoff before retry number `attempt`, counting from zero
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .retry_backoff_ms
+            .saturating_mul(1_u64.checked_shl(attempt).unwrap_or(u64::MAX));
+        Duration::from_millis(backoff.min(self.max_retry_backoff_ms))
+    }
+
+    /// Delay before retry number `attempt` of a response asking to wait `retry_after`
+    ///
+    /// `None` when the server asks to wait longer than `max_retry_backoff_ms`, not worth waiting for.
+    fn delay(&self, retry_after: Option<Duration>, attempt: u32) -> Option<Duration> {
+        match retry_after {
+            Some(delay) if delay > Duration::from_millis(self.max_retry_backoff_ms) => None,
+            Some(delay) => Some(delay),
+            None => Some(self.backoff(attempt)),
+        }
+    }
+
+    /// Send the request built by `f`, retrying connection errors and transient statuses
+    ///
+    /// The last response is returned as is once retries are exhausted, or when the server asks to wait
+    /// longer than `max_retry_backoff_ms`, so that the caller can inspect its status.
+    pub async fn send<F>(&self, f: F) -> reqwest::Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            let delay = match f().send().await {
+                Ok(resp) if !is_transient(resp.status()) => return Ok(resp),
+                Ok(resp) if attempt >= self.max_retries => return Ok(resp),
+                Err(e) if attempt >= self.max_retries || !is_transient_error(&e) => return Err(e),
+                Ok(resp) => match self.delay(retry_after(&resp), attempt) {
+                    Some(delay) => delay,
+                    None => return Ok(resp),
+                },
+                Err(_) => self.backoff(attempt),
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_transient(status: reqwest::StatusCode) -> bool {
+    use reqwest::StatusCode;
+
+    matches!(
+        status,
+        StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+pub fn is_transient_error(e: &reqwest::Error) -> bool {
+    e.is_connect() || e.is_timeout() || e.is_request() || e.is_body()
+}
+
+// https://www.rfc-editor.org/rfc/rfc9110#field.retry-after
+fn retry_after(resp: &reqwest::Response) -> Option<Duration> {
+    let value = resp.headers().get(reqwest::header::RETRY_AFTER)?;
+    parse_retry_after(value.to_str().ok()?, std::time::SystemTime::now())
+}
+
+fn parse_retry_after(value: &str, now: std::time::SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let options = RetryOptions {
+            max_retries: 10,
+            retry_backoff_ms: 100,
+            max_retry_backoff_ms: 1_000,
+        };
+        assert_eq!(options.backoff(0), Duration::from_millis(100));
+        assert_eq!(options.backoff(1), Duration::from_millis(200));
+        assert_eq!(options.backoff(3), Duration::from_millis(800));
+        assert_eq!(options.backoff(4), Duration::from_millis(1_000));
+        assert_eq!(options.backoff(100), Duration::from_millis(1_000));
+    }
+
+    #[test]
+    fn test_delay() {
+        let options = RetryOptions {
+            max_retries: 10,
+            retry_backoff_ms: 100,
+            max_retry_backoff_ms: 1_000,
+        };
+        assert_eq!(options.delay(None, 1), Some(Duration::from_millis(200)));
+        assert_eq!(
+            options.delay(Some(Duration::from_millis(900)), 1),
+            Some(Duration::from_millis(900))
+        );
+        assert_eq!(options.delay(Some(Duration::from_secs(3600)), 1), None);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:29:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+}
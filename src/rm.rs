@@ -1,6 +1,6 @@
 use clap::Args;
 
-use crate::container_dir;
+use crate::{container_dir, release_lower_dirs};
 
 #[derive(Debug, Args)]
 pub struct RmArgs {
@@ -10,13 +10,19 @@ pub struct RmArgs {
 impl RmArgs {
     pub fn run(self) -> anyhow::Result<()> {
         for name in self.containers {
-            let container = container_dir(&name);
-            #[cfg(target_os = "linux")]
-            {
-                crate::mounting::unmount(&name);
-            }
-            let _ = std::fs::remove_dir_all(&container);
+            remove_container(&name)?;
         }
         Ok(())
     }
 }
+
+pub(crate) fn remove_container(name: &str) -> anyhow::Result<()> {
+    let container = container_dir(name);
+    #[cfg(target_os = "linux")]
+    {
+        crate::mounting::unmount(name);
+    }
+    release_lower_dirs(name)?;
+    let _ = std::fs::remove_dir_all(&container);
+    Ok(())
+}
@@ -1,40 +1,34 @@
-use std::borrow::Cow;
-
 use clap::Args;
 
-use crate::PACKED_LAYER_DIR;
+use crate::{
+    gc,
+    image_reference::ImageReference,
+    image_store::{lock_pulls_out, read_manifest, ImageIndex},
+};
 
 #[derive(Debug, Args)]
 pub struct RmiArgs {
-    images: Vec<String>,
+    images: Vec<ImageReference>,
 }
 
 impl RmiArgs {
     pub fn run(self) -> anyhow::Result<()> {
+        // A pull in progress may be fetching blobs of the image for another one
+        let _lock = lock_pulls_out()?;
         for image in self.images {
-            let (image_left, image_right): (Cow<str>, Cow<str>) = match image.contains('/') {
-                true => image
-                    .split_once('/')
-                    .map(|(l, r)| (l.into(), r.into()))
-                    .unwrap(),
-                false => ("library".into(), image.into()),
+            let Some(manifest_digest) = ImageIndex::load()?.get(&image).map(str::to_string) else {
+                anyhow::bail!("No such image `{image}`");
             };
-
-            let layers = &PACKED_LAYER_DIR;
-            let layers = std::fs::read_dir(layers.as_path()).unwrap();
-            let mut layers_to_remove = vec![];
-            for layer in layers {
-                let layer = layer.unwrap();
-                let layer_name = layer.file_name();
-                let mut split = layer_name.to_str().unwrap().split('.');
-                let image_left_ = split.next().unwrap().to_string();
-                let image_right_ = split.next().unwrap().to_string();
-                if image_left == image_left_ && image_right == image_right_ {
-                    layers_to_remove.push(layer.path());
-                }
+            // Without its manifest there is nothing but the tag to remove
+            let manifest = read_manifest(&manifest_digest).ok();
+            if ImageIndex::update(|index| index.remove(&image))?.is_none() {
+                anyhow::bail!("No such image `{image}`");
             }
-            for layer in layers_to_remove {
-                std::fs::remove_file(layer).unwrap();
+            println!("Untagged: {image}");
+
+            if let Some(manifest) = manifest {
+                let garbage = gc::collect_image(&manifest_digest, &manifest)?;
+                gc::sweep(&garbage, false)?;
             }
         }
         Ok(())
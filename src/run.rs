@@ -1,17 +1,21 @@
 use crate::{
-    container_dir, execute_command, pid_file_path, process_alive, pull_image::pull, read_pid,
-    root_fs_path, write_pid,
+    container_dir, execute_command,
+    image_reference::ImageReference,
+    image_store::image_config,
+    pid_file_path, process_alive,
+    pull_image::{pull, unpack, unpack_flat, PullOptions, DEFAULT_REGISTRY},
+    read_pid, release_lower_dirs, root_fs_path, write_pid, ProcessConfig,
 };
 use anyhow::{Context, Result};
 use clap::Args;
 
 const DOCKER_EXPLORER: &str = "/usr/local/bin/docker-explorer";
-const DEFAULT_REGISTRY: &str = "https://registry.hub.docker.com";
 
 #[derive(Debug, Args)]
 pub struct RunArgs {
-    pub image: String,
-    pub command: String,
+    pub image: ImageReference,
+    /// Replaces the `Cmd` of the image
+    pub command: Option<String>,
     pub command_args: Vec<String>,
     #[clap(short, long, default_value_t = String::from("default"))]
     pub name: String,
@@ -19,15 +23,14 @@ pub struct RunArgs {
     pub force: bool,
     #[clap(short, long, default_value_t = String::from(DEFAULT_REGISTRY))]
     pub registry: String,
+    #[clap(flatten)]
+    pub pull_options: PullOptions,
 }
 
 impl RunArgs {
-    // Usage: your_docker.sh run <image> <command> <arg1> <arg2> ...
+    // Usage: your_docker.sh run <image> [command] [arg1] [arg2] ...
     pub fn run(self) -> Result<()> {
         let image = &self.image;
-        let command = &self.command;
-        let command = std::path::Path::new(command);
-        let command_args = &self.command_args;
 
         // Set up container
         let container = container_dir(&self.name);
@@ -42,6 +45,7 @@ impl RunArgs {
         {
             crate::mounting::unmount(&self.name);
         }
+        release_lower_dirs(&self.name)?;
         let _ = std::fs::remove_dir_all(&container);
         std::fs::create_dir_all(&container).unwrap();
 
@@ -53,27 +57,38 @@ impl RunArgs {
         write_pid(&pid_file_path);
 
         // Pull image
-        let name = self.name.clone();
-        tokio::runtime::Builder::new_multi_thread()
+        let runtime = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
-            .unwrap()
-            .block_on(async move {
-                pull(&self.registry, image, &name).await;
-            });
+            .unwrap();
+        runtime.block_on(pull(&self.registry, image, &self.pull_options))?;
+        let config = image_config(image)?;
+        let config = config.config().clone().unwrap_or_default();
+
+        // Like Docker, the command line replaces `Cmd` but is still passed to `Entrypoint`
+        let mut argv = config.entrypoint().clone().unwrap_or_default();
+        match &self.command {
+            Some(command) => {
+                argv.push(command.clone());
+                argv.extend(self.command_args.iter().cloned());
+            }
+            None => argv.extend(config.cmd().clone().unwrap_or_default()),
+        }
+        let (command, command_args) = argv
+            .split_first()
+            .with_context(|| format!("no command given and image `{image}` has no default"))?;
+        let command = std::path::Path::new(command);
 
         // Copy command file `docker-explorer` to the root directory
         let docker_explorer = std::path::Path::new(DOCKER_EXPLORER);
         if docker_explorer.exists() {
-            let command_file = docker_explorer.strip_prefix("/").with_context(|| {
-                format!("command '{}' is not an absolute path", command.display())
-            })?;
+            let command_file = docker_explorer.strip_prefix("/").unwrap();
             let command_file = root.join(command_file);
             std::fs::create_dir_all(command_file.parent().unwrap()).unwrap();
-            std::fs::copy(command, &command_file).with_context(|| {
+            std::fs::copy(docker_explorer, &command_file).with_context(|| {
                 format!(
                     "failed to copy '{}' to '{}'",
-                    command.display(),
+                    docker_explorer.display(),
                     command_file.display()
                 )
             })?;
@@ -85,12 +100,29 @@ impl RunArgs {
         let null = dev.join("null");
         std::fs::File::create(null).unwrap();
 
+        // Stack the layers with overlayfs.
+        // Its whiteouts take the same privileges as mounting it, so failing to write them rules it out as well.
         #[cfg(target_os = "linux")]
-        {
-            crate::mounting::mount_root_fs(&self.name);
+        let mounted = match runtime.block_on(unpack(image, &self.name)) {
+            Ok(()) => crate::mounting::mount_root_fs(&self.name).is_ok(),
+            Err(e) => {
+                eprintln!("Cannot unpack `{image}` for overlayfs, unpacking it flat: {e:#}");
+                false
+            }
+        };
+        #[cfg(not(target_os = "linux"))]
+        let mounted = false;
+        if !mounted {
+            // Without overlayfs the layers are stacked right in the root directory
+            runtime.block_on(unpack_flat(image, &root))?;
         }
 
         // Execute the command
-        execute_command(command, command_args, &root)
+        let process = ProcessConfig {
+            env: config.env().clone(),
+            working_dir: config.working_dir().clone().filter(|dir| !dir.is_empty()),
+            user: config.user().clone(),
+        };
+        execute_command(command, command_args, &root, &process)
     }
 }
@@ -0,0 +1,74 @@
+use clap::{Args, Subcommand};
+
+use crate::{
+    gc, image_store::lock_pulls_out, pid_file_path, process_alive, read_pid, rm::remove_container,
+    CONTAINERS,
+};
+
+#[derive(Debug, Args)]
+pub struct SystemArgs {
+    #[clap(subcommand)]
+    sub_command: SystemCommand,
+}
+
+#[derive(Debug, Subcommand)]
+enum SystemCommand {
+    Prune(PruneArgs),
+}
+
+impl SystemArgs {
+    pub fn run(self) -> anyhow::Result<()> {
+        match self.sub_command {
+            SystemCommand::Prune(prune) => prune.run(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct PruneArgs {
+    /// List what would be deleted without deleting anything
+    #[clap(long, default_value_t = false)]
+    pub dry_run: bool,
+}
+
+impl PruneArgs {
+    // Usage: your_docker.sh system prune [--dry-run]
+    pub fn run(self) -> anyhow::Result<()> {
+        let _lock = lock_pulls_out()?;
+        let stopped = stopped_containers()?;
+        // Layers of the stopped containers are garbage as well, so mark as if those were gone already
+        let garbage = gc::collect(|name| !stopped.iter().any(|stopped| stopped == name))?;
+
+        for name in &stopped {
+            if !self.dry_run {
+                remove_container(name)?;
+            }
+            let verb = if self.dry_run {
+                "Would delete"
+            } else {
+                "Deleted"
+            };
+            println!("{verb} container: {name}");
+        }
+        gc::sweep(&garbage, self.dry_run)
+    }
+}
+
+/// Containers whose process is not running anymore
+fn stopped_containers() -> anyhow::Result<Vec<String>> {
+    let containers = match std::fs::read_dir(CONTAINERS.as_path()) {
+        Ok(containers) => containers,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut stopped = vec![];
+    for container in containers {
+        let name = container?.file_name().to_string_lossy().into_owned();
+        match read_pid(pid_file_path(&name)) {
+            Some(pid) if process_alive(pid) => (),
+            _ => stopped.push(name),
+        }
+    }
+    stopped.sort();
+    Ok(stopped)
+}
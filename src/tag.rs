@@ -0,0 +1,23 @@
+use clap::Args;
+
+use crate::{image_reference::ImageReference, image_store::ImageIndex};
+
+#[derive(Debug, Args)]
+pub struct TagArgs {
+    pub source: ImageReference,
+    pub target: ImageReference,
+}
+
+impl TagArgs {
+    // Usage: your_docker.sh tag <source> <target>
+    pub fn run(self) -> anyhow::Result<()> {
+        ImageIndex::update(|index| {
+            let manifest_digest = index
+                .get(&self.source)
+                .ok_or_else(|| anyhow::anyhow!("No such image `{}`", self.source))?
+                .to_string();
+            index.insert(&self.target, &manifest_digest);
+            Ok(())
+        })?
+    }
+}
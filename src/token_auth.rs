@@ -1,74 +1,434 @@
 // https://distribution.github.io/distribution/spec/auth/token/
+// https://distribution.github.io/distribution/spec/auth/oauth/
 
-use crate::www_authenticate::WwwAuthenticate;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-pub async fn pass_token_auth<F>(f: F) -> reqwest::Response
-where
-    F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
-{
-    let client = reqwest::Client::new();
+use anyhow::Context;
 
-    // Attempt to begin a push/pull operation with the registry.
-    let resp = f(&client).send().await.unwrap();
-    if resp.status().as_u16() != 401 {
-        return resp;
+use crate::{
+    credentials::Credentials,
+    retry::RetryOptions,
+    www_authenticate::{Challenge, WwwAuthenticate},
+};
+
+/// Tokens are dropped this long before they expire so that they do not run out mid-request
+const TOKEN_EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+/// Lifetime of tokens issued without `expires_in`
+const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+/// Identifies us to OAuth2 authorization services
+const OAUTH_CLIENT_ID: &str = "mydocker";
+
+/// HTTP client for one registry, sharing its connection pool and Bearer tokens across requests
+///
+/// The way to authenticate is picked from the registry's challenge: a Bearer token from its
+/// authorization service, the stored credentials for Basic auth, or none at all.
+///
+/// With credentials, Bearer tokens come from the OAuth2 password grant, which exchanges the password once for
+/// a refresh token kept for as long as the client lives. Authorization services that do not speak OAuth2 get
+/// the token GET instead.
+///
+/// Cloning is cheap and clones share the pool and the tokens.
+#[derive(Debug, Clone)]
+pub struct RegistryClient {
+    inner: Arc<Inner>,
+    /// Requested for challenges that do not say which scope they want
+    default_scope: Option<String>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    client: reqwest::Client,
+    retry: RetryOptions,
+    credentials: Option<Credentials>,
+    tokens: Mutex<HashMap<TokenKey, CachedToken>>,
+    /// OAuth2 refresh tokens by realm and service, exchanged for access tokens instead of the password
+    refresh_tokens: Mutex<HashMap<(String, Option<String>), String>>,
+    /// Realms and services found not to speak OAuth2, which only get the token GET
+    no_oauth: Mutex<HashSet<(String, Option<String>)>>,
+    /// The challenge of the last `401`, answered up front as the next request most likely gets it too
+    last_challenge: Mutex<Option<Scheme>>,
+}
+
+/// Supported ways a registry asks to be authenticated
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Scheme {
+    // https://www.rfc-editor.org/rfc/rfc7617
+    Basic,
+    Bearer(TokenKey),
+}
+
+/// What is sent in the `Authorization` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Authorization {
+    Basic,
+    Bearer(String),
+}
+
+/// What a token grants access to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct TokenKey {
+    realm: String,
+    service: Option<String>,
+    scopes: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    expires_at: Instant,
+}
+
+impl RegistryClient {
+    /// With `credentials` tokens are requested on behalf of that account and Basic challenges are
+    /// answered, otherwise the registry is accessed anonymously
+    pub fn new(retry: RetryOptions, credentials: Option<Credentials>) -> Self {
+        Self::with_http_client(reqwest::Client::new(), retry, credentials)
     }
 
-    // If the registry requires authorization it will return a `401 Unauthorized`` HTTP response with information on how to authenticate.
-    let how = resp.headers().get("www-authenticate").unwrap();
-    let how = how.to_str().unwrap();
-    let how: WwwAuthenticate = how.parse().unwrap();
-    assert_eq!(how.scheme(), "Bearer");
+    /// Send the requests with `client`, e.g. one trusting a private CA
+    pub fn with_http_client(
+        client: reqwest::Client,
+        retry: RetryOptions,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                retry,
+                credentials,
+                tokens: Mutex::default(),
+                refresh_tokens: Mutex::default(),
+                no_oauth: Mutex::default(),
+                last_challenge: Mutex::default(),
+            }),
+            default_scope: None,
+        }
+    }
 
-    // The registry client makes a request to the authorization service for a Bearer token.
-    let mut query = String::new();
-    let queries = how.pairs().iter().filter(|(k, _)| *k != "realm");
-    for (i, (key, value)) in queries.enumerate() {
-        if i != 0 {
-            query.push('&');
+    /// A client sharing the pool and the tokens of this one, asking for `scope` when challenges omit it,
+    /// e.g. `repository:library/ubuntu:pull`
+    pub fn with_default_scope(&self, scope: String) -> Self {
+        Self {
+            default_scope: Some(scope),
+            ..self.clone()
         }
-        query.push_str(key);
-        query.push('=');
-        query.push_str(value);
-    }
-    let token_url = format!("{}?{}", how.pairs().get("realm").unwrap(), query);
-
-    // The authorization service returns an opaque Bearer token representing the client’s authorized access.
-    let resp: models::TokenResponse = client
-        .get(token_url)
-        .send()
-        .await
-        .unwrap()
-        .json()
-        .await
-        .unwrap();
+    }
+
+    pub fn retry(&self) -> &RetryOptions {
+        &self.inner.retry
+    }
+
+    /// Send the request built by `f`, authenticating the way the registry asks for if it does
+    pub async fn send<F>(&self, f: F) -> anyhow::Result<reqwest::Response>
+    where
+        F: Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    {
+        let Inner { client, retry, .. } = self.inner.as_ref();
+
+        // Attempt to begin a push/pull operation with the registry.
+        // Answering the last challenge up front saves the round trip to get challenged again.
+        let sent = self.last_authorization();
+        let resp = retry
+            .send(|| self.authorize(f(client), sent.as_ref()))
+            .await?;
+        if resp.status().as_u16() != 401 {
+            return Ok(resp);
+        }
+
+        // If the registry requires authorization it will return a `401 Unauthorized`` HTTP response with information on how to authenticate.
+        let Some(scheme) = self.pick_scheme(&challenges(&resp)?)? else {
+            // Nothing we can answer, the caller gets the `401`
+            return Ok(resp);
+        };
+        let authorization = match &scheme {
+            // The registry just turned the credentials down
+            Scheme::Basic if sent == Some(Authorization::Basic) => return Ok(resp),
+            Scheme::Basic => Authorization::Basic,
+            // A cached token the registry just turned down is not worth sending again
+            Scheme::Bearer(key) => match self.cached_token(key) {
+                Some(token) if sent != Some(Authorization::Bearer(token.clone())) => {
+                    Authorization::Bearer(token)
+                }
+                _ => Authorization::Bearer(self.fetch_token(key).await?),
+            },
+        };
+        *self.inner.last_challenge.lock().unwrap() = Some(scheme);
+
+        // The Registry authorizes the client by validating the Bearer token and the claim set embedded within it and begins the push/pull session as usual.
+        let resp = retry
+            .send(|| self.authorize(f(client), Some(&authorization)))
+            .await?;
+        Ok(resp)
+    }
+
+    /// Bearer is preferred as it does not hand the password to the registry on every request
+    fn pick_scheme(&self, challenges: &[Challenge]) -> anyhow::Result<Option<Scheme>> {
+        if let Some(how) = challenges.iter().find(|how| how.is_scheme("Bearer")) {
+            let mut key = TokenKey {
+                realm: how
+                    .param("realm")
+                    .ok_or_else(|| anyhow::anyhow!("Bearer challenge without realm"))?
+                    .to_string(),
+                service: how.param("service").map(str::to_string),
+                // Several scopes come space separated or in several parameters
+                scopes: how
+                    .params()
+                    .iter()
+                    .filter(|(name, _)| name == "scope")
+                    .flat_map(|(_, scopes)| scopes.split_whitespace())
+                    .map(str::to_string)
+                    .collect(),
+            };
+            if key.scopes.is_empty() {
+                key.scopes.extend(self.default_scope.clone());
+            }
+            return Ok(Some(Scheme::Bearer(key)));
+        }
+        if let Some(how) = challenges.iter().find(|how| how.is_scheme("Basic")) {
+            if self.inner.credentials.is_none() {
+                let realm = how.param("realm").unwrap_or_default();
+                anyhow::bail!(
+                    "Basic authentication required (realm `{realm}`), log in with `mydocker login`"
+                );
+            }
+            return Ok(Some(Scheme::Basic));
+        }
+        Ok(None)
+    }
+
+    fn last_authorization(&self) -> Option<Authorization> {
+        let scheme = self.inner.last_challenge.lock().unwrap().clone()?;
+        match scheme {
+            Scheme::Basic => Some(Authorization::Basic),
+            Scheme::Bearer(key) => self.cached_token(&key).map(Authorization::Bearer),
+        }
+    }
+
+    fn cached_token(&self, key: &TokenKey) -> Option<String> {
+        let mut tokens = self.inner.tokens.lock().unwrap();
+        match tokens.get(key) {
+            Some(cached) if cached.expires_at > Instant::now() => Some(cached.token.clone()),
+            Some(_) => {
+                tokens.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
 
     // The client retries the original request with the Bearer token embedded in the request’s Authorization header.
-    let authorization = format!("Bearer {}", resp.token());
-
-    // The Registry authorizes the client by validating the Bearer token and the claim set embedded within it and begins the push/pull session as usual.
-    f(&client)
-        .header("Authorization", authorization)
-        .send()
-        .await
-        .unwrap()
+    fn authorize(
+        &self,
+        req: reqwest::RequestBuilder,
+        authorization: Option<&Authorization>,
+    ) -> reqwest::RequestBuilder {
+        match (authorization, &self.inner.credentials) {
+            (Some(Authorization::Bearer(token)), _) => req.bearer_auth(token),
+            (Some(Authorization::Basic), Some(credentials)) => {
+                req.basic_auth(&credentials.username, Some(&credentials.password))
+            }
+            _ => req,
+        }
+    }
+
+    async fn fetch_token(&self, key: &TokenKey) -> anyhow::Result<String> {
+        // The registry client makes a request to the authorization service for a Bearer token.
+        let requested_at = Instant::now();
+        let (token, lifetime) = match self.post_token(key).await? {
+            Some(token) => token,
+            None => self.get_token(key).await?,
+        };
+
+        self.inner.tokens.lock().unwrap().insert(
+            key.clone(),
+            CachedToken {
+                token: token.clone(),
+                expires_at: requested_at + lifetime.saturating_sub(TOKEN_EXPIRY_MARGIN),
+            },
+        );
+        Ok(token)
+    }
+
+    /// Token from the OAuth2 flow, `None` for the token GET instead
+    ///
+    /// That is without credentials, or when the authorization service turns the request down.
+    async fn post_token(&self, key: &TokenKey) -> anyhow::Result<Option<(String, Duration)>> {
+        let Inner {
+            client,
+            retry,
+            credentials,
+            refresh_tokens,
+            no_oauth,
+            ..
+        } = self.inner.as_ref();
+        let realm = (key.realm.clone(), key.service.clone());
+        if no_oauth.lock().unwrap().contains(&realm) {
+            return Ok(None);
+        }
+
+        loop {
+            let refresh_token = refresh_tokens.lock().unwrap().get(&realm).cloned();
+            let mut form = vec![("client_id", OAUTH_CLIENT_ID)];
+            match (&refresh_token, credentials) {
+                (Some(refresh_token), _) => {
+                    form.push(("grant_type", "refresh_token"));
+                    form.push(("refresh_token", refresh_token));
+                }
+                (None, Some(credentials)) => {
+                    form.push(("grant_type", "password"));
+                    form.push(("username", &credentials.username));
+                    form.push(("password", &credentials.password));
+                    // Ask for a refresh token so that the password is sent only once
+                    form.push(("access_type", "offline"));
+                }
+                (None, None) => return Ok(None),
+            }
+            form.extend(
+                key.service
+                    .iter()
+                    .map(|service| ("service", service.as_str())),
+            );
+            form.extend(key.scopes.iter().map(|scope| ("scope", scope.as_str())));
+
+            let resp = retry.send(|| client.post(&key.realm).form(&form)).await?;
+            let status = resp.status();
+            if status.is_client_error() || status == reqwest::StatusCode::NOT_IMPLEMENTED {
+                if refresh_token.is_some() {
+                    // Expired or revoked, start over
+                    refresh_tokens.lock().unwrap().remove(&realm);
+                    continue;
+                }
+                // No OAuth2 there, or not with this grant
+                if matches!(status.as_u16(), 404 | 405 | 501) {
+                    no_oauth.lock().unwrap().insert(realm);
+                }
+                return Ok(None);
+            }
+            let body = resp
+                .error_for_status()
+                .with_context(|| format!("OAuth2 token request to `{}`", key.realm))?
+                .bytes()
+                .await?;
+            let resp = models::TokenResponse::parse(&body, &key.realm)?;
+
+            if let Some(refresh_token) = resp.refresh_token() {
+                refresh_tokens
+                    .lock()
+                    .unwrap()
+                    .insert(realm, refresh_token.clone());
+            }
+            return Ok(Some((resp.bearer_token()?.to_string(), resp.lifetime())));
+        }
+    }
+
+    async fn get_token(&self, key: &TokenKey) -> anyhow::Result<(String, Duration)> {
+        let Inner {
+            client,
+            retry,
+            credentials,
+            ..
+        } = self.inner.as_ref();
+
+        let mut query = vec![];
+        query.extend(
+            key.service
+                .iter()
+                .map(|service| ("service", service.as_str())),
+        );
+        query.extend(key.scopes.iter().map(|scope| ("scope", scope.as_str())));
+        if let Some(credentials) = credentials {
+            query.push(("account", &credentials.username));
+        }
+
+        // The authorization service returns an opaque Bearer token representing the client’s authorized access.
+        let body = retry
+            .send(|| {
+                let req = client.get(&key.realm).query(&query);
+                match credentials {
+                    Some(credentials) => {
+                        req.basic_auth(&credentials.username, Some(&credentials.password))
+                    }
+                    None => req,
+                }
+            })
+            .await?
+            .error_for_status()
+            .with_context(|| format!("token request to `{}`", key.realm))?
+            .bytes()
+            .await?;
+        let resp = models::TokenResponse::parse(&body, &key.realm)?;
+
+        Ok((resp.bearer_token()?.to_string(), resp.lifetime()))
+    }
+}
+
+/// Challenges of all the `WWW-Authenticate` headers of `resp`
+fn challenges(resp: &reqwest::Response) -> anyhow::Result<Vec<Challenge>> {
+    let mut challenges = vec![];
+    for how in resp.headers().get_all(reqwest::header::WWW_AUTHENTICATE) {
+        let how: WwwAuthenticate = how.to_str()?.parse()?;
+        challenges.extend(how.challenges().iter().cloned());
+    }
+    Ok(challenges)
 }
 
 #[allow(dead_code)]
 mod models {
+    use std::time::Duration;
+
+    use anyhow::Context;
     use getset::{CopyGetters, Getters};
     use serde::Deserialize;
 
+    use super::DEFAULT_TOKEN_LIFETIME;
+
+    /// Every field is optional as authorization services only agree on sending one of `token` and `access_token`
     #[derive(Debug, Clone, Deserialize, Getters, CopyGetters)]
     pub struct TokenResponse {
         #[getset(get = "pub")]
-        token: String,
+        token: Option<String>,
+        /// OAuth2 name of `token`
+        #[getset(get = "pub")]
+        access_token: Option<String>,
         #[getset(get = "pub")]
-        access_token: String,
+        refresh_token: Option<String>,
         #[getset(get_copy = "pub")]
-        expires_in: usize,
+        expires_in: Option<u64>,
         #[getset(get = "pub")]
-        issued_at: String,
+        issued_at: Option<String>,
+    }
+
+    impl TokenResponse {
+        /// `realm` only shows in the error
+        pub fn parse(body: &[u8], realm: &str) -> anyhow::Result<Self> {
+            serde_json::from_slice(body).with_context(|| {
+                format!(
+                    "malformed token response from `{realm}`: {}",
+                    // Enough of an error page to tell what it is
+                    String::from_utf8_lossy(&body[..body.len().min(200)])
+                )
+            })
+        }
+
+        /// `token`, or `access_token` when that is all there is
+        pub fn bearer_token(&self) -> anyhow::Result<&str> {
+            [&self.token, &self.access_token]
+                .into_iter()
+                .flatten()
+                .find(|token| !token.is_empty())
+                .map(String::as_str)
+                .ok_or_else(|| anyhow::anyhow!("token response without `token` or `access_token`"))
+        }
+
+        /// `expires_in`, which defaults to 60 seconds
+        pub fn lifetime(&self) -> Duration {
+            self.expires_in
+                .map_or(DEFAULT_TOKEN_LIFETIME, Duration::from_secs)
+        }
     }
 }
 
@@ -79,9 +439,91 @@ mod tests {
     #[tokio::test]
     async fn test_pass_token_auth() {
         let url = "https://registry.hub.docker.com/v2/";
-        let resp = pass_token_auth(move |client| client.get(url)).await;
+        let client = RegistryClient::new(RetryOptions::default(), None);
+        let resp = client.send(move |client| client.get(url)).await.unwrap();
         dbg!(&resp);
         assert!(resp.status().is_success());
         dbg!(&resp.text().await.unwrap());
+
+        // The token of the first challenge is reused
+        let resp = client.send(move |client| client.get(url)).await.unwrap();
+        assert!(resp.status().is_success());
+        assert_eq!(client.inner.tokens.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_token_response() {
+        let parse = |body: &str| models::TokenResponse::parse(body.as_bytes(), "https://auth");
+
+        let docker = parse(
+            r#"{"token": "a", "access_token": "a", "expires_in": 300, "issued_at": "2023-11-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        assert_eq!(docker.bearer_token().unwrap(), "a");
+        assert_eq!(docker.lifetime(), Duration::from_secs(300));
+
+        let ghcr = parse(r#"{"token": "b"}"#).unwrap();
+        assert_eq!(ghcr.bearer_token().unwrap(), "b");
+        assert_eq!(ghcr.lifetime(), DEFAULT_TOKEN_LIFETIME);
+
+        let oauth = parse(r#"{"access_token": "c", "refresh_token": "d"}"#).unwrap();
+        assert_eq!(oauth.bearer_token().unwrap(), "c");
+        assert_eq!(oauth.refresh_token().as_deref(), Some("d"));
+
+        assert!(parse("{}").unwrap().bearer_token().is_err());
+        let err = parse("<html>").unwrap_err();
+        assert!(err.to_string().contains("https://auth"), "{err}");
+    }
+
+    #[test]
+    fn test_pick_scheme() {
+        let challenges = |header: &str| {
+            let how: WwwAuthenticate = header.parse().unwrap();
+            how.challenges().clone()
+        };
+        let anonymous = RegistryClient::new(RetryOptions::default(), None);
+        let alice = RegistryClient::new(
+            RetryOptions::default(),
+            Some(Credentials {
+                username: "alice".to_string(),
+                password: "s3cr3t".to_string(),
+            }),
+        );
+
+        let both = challenges(r#"Basic realm="r", Bearer realm="https://auth", service="s""#);
+        assert_eq!(
+            alice.pick_scheme(&both).unwrap(),
+            Some(Scheme::Bearer(TokenKey {
+                realm: "https://auth".to_string(),
+                service: Some("s".to_string()),
+                scopes: vec![],
+            }))
+        );
+        let scoped = challenges(
+            r#"Bearer realm="https://auth", scope="repository:a:pull repository:b:pull", scope="registry:catalog:*""#,
+        );
+        let Some(Scheme::Bearer(key)) = alice.pick_scheme(&scoped).unwrap() else {
+            panic!("not a Bearer challenge");
+        };
+        assert_eq!(
+            key.scopes,
+            [
+                "repository:a:pull",
+                "repository:b:pull",
+                "registry:catalog:*"
+            ]
+        );
+        let unscoped = challenges(r#"Bearer realm="https://auth""#);
+        let alice_pulling = alice.with_default_scope("repository:a:pull".to_string());
+        let Some(Scheme::Bearer(key)) = alice_pulling.pick_scheme(&unscoped).unwrap() else {
+            panic!("not a Bearer challenge");
+        };
+        assert_eq!(key.scopes, ["repository:a:pull"]);
+
+        let basic = challenges(r#"basic realm="r""#);
+        assert_eq!(alice.pick_scheme(&basic).unwrap(), Some(Scheme::Basic));
+        assert!(anonymous.pick_scheme(&basic).is_err());
+        let other = challenges("Negotiate YII=");
+        assert_eq!(alice.pick_scheme(&other).unwrap(), None);
     }
 }
@@ -0,0 +1,107 @@
+// https://github.com/opencontainers/image-spec/blob/main/config.md#properties
+//
+// `User` is one of `user`, `uid`, `user:group`, `uid:gid`, `uid:group` or `user:gid`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserIds {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Resolve a `User` spec against the contents of the container's `/etc/passwd` and `/etc/group`
+///
+/// Without an explicit group, the primary group of the user is used, or `0` if the user is not listed.
+pub fn resolve_user(spec: &str, passwd: &str, group: &str) -> anyhow::Result<UserIds> {
+    let (user, group_spec) = match spec.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (spec, None),
+    };
+
+    // passwd <- name:password:uid:gid:...
+    let passwd_entry = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 4)
+        .find(|fields| fields[0] == user || fields[2] == user);
+    let uid = match (user.parse::<u32>(), &passwd_entry) {
+        (Ok(uid), _) => uid,
+        (Err(_), Some(fields)) => fields[2].parse()?,
+        (Err(_), None) => anyhow::bail!("no user `{user}` in `/etc/passwd`"),
+    };
+
+    let gid = match group_spec {
+        Some(group_spec) => match group_spec.parse::<u32>() {
+            Ok(gid) => gid,
+            // group <- name:password:gid:members
+            Err(_) => group
+                .lines()
+                .map(|line| line.split(':').collect::<Vec<_>>())
+                .find(|fields| fields.len() >= 3 && fields[0] == group_spec)
+                .map(|fields| fields[2].parse())
+                .transpose()?
+                .ok_or_else(|| anyhow::anyhow!("no group `{group_spec}` in `/etc/group`"))?,
+        },
+        None => match &passwd_entry {
+            Some(fields) => fields[3].parse()?,
+            None => 0,
+        },
+    };
+
+    Ok(UserIds { uid, gid })
+}
+
+/// Switch the current process to `ids`, dropping the supplementary groups
+///
+/// Runs between `fork` and `exec`, so it only makes async-signal-safe calls.
+pub fn switch_user(ids: UserIds) -> std::io::Result<()> {
+    // The group goes first as changing it takes the privileges that changing the user drops
+    unsafe {
+        if libc::geteuid() == 0 && libc::setgroups(0, std::ptr::null()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if libc::setgid(ids.gid) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if libc::setuid(ids.uid) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWD: &str =
+        "root:x:0:0:root:/root:/bin/sh\nnobody:x:65534:65533:nobody:/:/sbin/nologin\n";
+    const GROUP: &str = "root:x:0:root\nnogroup:x:65533:\nwheel:x:10:root\n";
+
+    #[test]
+    fn test_resolve_user() {
+        assert_eq!(
+            resolve_user("nobody", PASSWD, GROUP).unwrap(),
+            UserIds {
+                uid: 65534,
+                gid: 65533
+            }
+        );
+        assert_eq!(
+            resolve_user("1000", PASSWD, GROUP).unwrap(),
+            UserIds { uid: 1000, gid: 0 }
+        );
+        assert_eq!(
+            resolve_user("nobody:wheel", PASSWD, GROUP).unwrap(),
+            UserIds {
+                uid: 65534,
+                gid: 10
+            }
+        );
+        assert_eq!(
+            resolve_user("0:42", PASSWD, GROUP).unwrap(),
+            UserIds { uid: 0, gid: 42 }
+        );
+        assert!(resolve_user("alice", PASSWD, GROUP).is_err());
+        assert!(resolve_user("root:staff", PASSWD, GROUP).is_err());
+    }
+}
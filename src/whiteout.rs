@@ -0,0 +1,287 @@
+// https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+
+use std::{
+    collections::HashSet,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Context;
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteoutMode {
+    /// Each layer keeps its own directory and whiteouts become the ones overlayfs understands
+    Overlay,
+    /// Layers are unpacked on top of each other and whited out files are deleted right away
+    Apply,
+}
+
+/// Unpack a layer tarball into `dst`, handling whiteout entries according to `mode`
+pub async fn unpack_layer<R>(tar: R, dst: &Path, mode: WhiteoutMode) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut archive = tokio_tar::Archive::new(tar);
+    let mut entries = archive.entries()?;
+    // Paths coming from this layer, which an opaque whiteout must not hide
+    let mut unpacked = HashSet::new();
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        let path = entry.path()?;
+        let path = normalize(&path).with_context(|| format!("unsafe path `{}`", path.display()))?;
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+
+        if file_name.starts_with(WHITEOUT_PREFIX) {
+            check_no_symlinks(dst, parent).await?;
+        }
+
+        if file_name == OPAQUE_WHITEOUT {
+            let dir = dst.join(parent);
+            tokio::fs::create_dir_all(&dir).await?;
+            match mode {
+                WhiteoutMode::Overlay => set_opaque(&dir)?,
+                WhiteoutMode::Apply => clear_dir(&dir, parent, &unpacked).await?,
+            }
+        } else if let Some(name) = file_name.strip_prefix(WHITEOUT_PREFIX) {
+            if matches!(name, "" | "." | "..") {
+                anyhow::bail!("invalid whiteout `{}`", path.display());
+            }
+            let target = dst.join(parent).join(name);
+            remove_path(&target).await?;
+            if mode == WhiteoutMode::Overlay {
+                tokio::fs::create_dir_all(dst.join(parent)).await?;
+                make_whiteout(&target)?;
+            }
+        } else {
+            entry.unpack_in(dst).await?;
+            unpacked.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Strip `.` and `/` from a tar entry path and refuse to climb out with `..`
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir | Component::RootDir => (),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(normalized)
+}
+
+/// Refuse to follow symlinks on the way from `dst` to `relative`, which a layer could point anywhere
+/// on the host
+async fn check_no_symlinks(dst: &Path, relative: &Path) -> anyhow::Result<()> {
+    let mut path = dst.to_path_buf();
+    for component in relative.components() {
+        path.push(component);
+        match tokio::fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                anyhow::bail!("whiteout through symlink `{}`", path.display())
+            }
+            Ok(_) => (),
+            // What doesn't exist yet is created as plain directories
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Delete everything in `dir` that lower layers put there
+async fn clear_dir(dir: &Path, relative: &Path, unpacked: &HashSet<PathBuf>) -> anyhow::Result<()> {
+    let mut children = tokio::fs::read_dir(dir).await?;
+    while let Some(child) = children.next_entry().await? {
+        let child_relative = relative.join(child.file_name());
+        if unpacked
+            .iter()
+            .any(|path| path.starts_with(&child_relative))
+        {
+            continue;
+        }
+        remove_path(&child.path()).await?;
+    }
+    Ok(())
+}
+
+async fn remove_path(path: &Path) -> std::io::Result<()> {
+    let metadata = match tokio::fs::symlink_metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    match metadata.is_dir() {
+        true => tokio::fs::remove_dir_all(path).await,
+        false => tokio::fs::remove_file(path).await,
+    }
+}
+
+// https://docs.kernel.org/filesystems/overlayfs.html#whiteouts-and-opaque-directories
+#[cfg(target_os = "linux")]
+fn make_whiteout(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let res = unsafe { libc::mknod(c_path.as_ptr(), libc::S_IFCHR, libc::makedev(0, 0)) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to create whiteout `{}`", path.display()));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_opaque(dir: &Path) -> anyhow::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    let name = b"trusted.overlay.opaque\0";
+    let value = b"y";
+    let res = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            name.as_ptr() as *const libc::c_char,
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to mark `{}` opaque", dir.display()));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn make_whiteout(path: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("overlay whiteout `{}` requires Linux", path.display())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_opaque(dir: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("opaque directory `{}` requires Linux", dir.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn tarball(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tokio_tar::Builder::new(vec![]);
+        for (path, data) in entries {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).await.unwrap();
+        }
+        builder.into_inner().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_whiteouts() {
+        let dst = tempfile::tempdir().unwrap();
+        let lower = tarball(&[
+            ("etc/passwd", b"root"),
+            ("etc/motd", b"hi"),
+            ("var/cache/a", b"a"),
+            ("var/cache/b", b"b"),
+        ])
+        .await;
+        unpack_layer(lower.as_slice(), dst.path(), WhiteoutMode::Apply)
+            .await
+            .unwrap();
+
+        let upper = tarball(&[
+            ("etc/.wh.motd", b""),
+            ("var/cache/c", b"c"),
+            ("var/cache/.wh..wh..opq", b""),
+        ])
+        .await;
+        unpack_layer(upper.as_slice(), dst.path(), WhiteoutMode::Apply)
+            .await
+            .unwrap();
+
+        assert!(dst.path().join("etc/passwd").exists());
+        assert!(!dst.path().join("etc/motd").exists());
+        assert!(!dst.path().join("etc/.wh.motd").exists());
+        assert!(!dst.path().join("var/cache/a").exists());
+        assert!(!dst.path().join("var/cache/b").exists());
+        assert!(dst.path().join("var/cache/c").exists());
+        assert!(!dst.path().join("var/cache/.wh..wh..opq").exists());
+    }
+
+    async fn symlink_tarball(link: &str, target: &Path, entries: &[&str]) -> Vec<u8> {
+        let mut builder = tokio_tar::Builder::new(vec![]);
+        let mut header = tokio_tar::Header::new_gnu();
+        header.set_entry_type(tokio_tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        header.set_link_name(target).unwrap();
+        header.set_cksum();
+        builder
+            .append_data(&mut header, link, &[][..])
+            .await
+            .unwrap();
+        for path in entries {
+            let mut header = tokio_tar::Header::new_gnu();
+            header.set_size(0);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, &[][..])
+                .await
+                .unwrap();
+        }
+        builder.into_inner().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_whiteout_through_symlink() {
+        let host = tempfile::tempdir().unwrap();
+        std::fs::write(host.path().join("victim"), b"").unwrap();
+
+        for mode in [WhiteoutMode::Apply, WhiteoutMode::Overlay] {
+            for whiteout in ["evil/.wh.victim", "evil/.wh..wh..opq"] {
+                let dst = tempfile::tempdir().unwrap();
+                let layer = symlink_tarball("evil", host.path(), &[whiteout]).await;
+                assert!(unpack_layer(layer.as_slice(), dst.path(), mode)
+                    .await
+                    .is_err());
+                assert!(host.path().join("victim").exists(), "{mode:?} {whiteout}");
+            }
+        }
+
+        let dst = tempfile::tempdir().unwrap();
+        for whiteout in ["etc/.wh..", "etc/.wh...", "etc/.wh."] {
+            let layer = tarball(&[(whiteout, b"")]).await;
+            assert!(
+                unpack_layer(layer.as_slice(), dst.path(), WhiteoutMode::Apply)
+                    .await
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize(Path::new("./etc/passwd")),
+            Some(PathBuf::from("etc/passwd"))
+        );
+        assert_eq!(normalize(Path::new("etc/../../passwd")), None);
+    }
+}
@@ -1,97 +1,192 @@
-use std::{collections::HashMap, str::FromStr};
+// https://www.rfc-editor.org/rfc/rfc7235#section-4.1
+//
+// WWW-Authenticate = 1#challenge
+// challenge        = auth-scheme [ 1*SP ( token68 / #auth-param ) ]
+// auth-param       = token BWS "=" BWS ( token / quoted-string )
+// token68          = 1*( ALPHA / DIGIT / "-" / "." / "_" / "~" / "+" / "/" ) *"="
 
-use chumsky::prelude::*;
+use std::{fmt, ops::Range, str::FromStr};
+
+use chumsky::{prelude::*, Stream};
 use getset::Getters;
 use once_cell::sync::Lazy;
 
+type Spanned<T> = (T, Range<usize>);
+
 #[allow(clippy::type_complexity)]
-static LEXER: Lazy<Box<dyn Parser<char, Vec<Token>, Error = Simple<char>> + Sync + Send>> =
+static LEXER: Lazy<Box<dyn Parser<char, Vec<Spanned<Token>>, Error = Simple<char>> + Sync + Send>> =
     Lazy::new(|| Box::new(lexer()));
 static PARSER: Lazy<Box<dyn Parser<Token, WwwAuthenticate, Error = Simple<Token>> + Sync + Send>> =
     Lazy::new(|| Box::new(parser()));
 
 #[derive(Debug, Getters)]
 pub struct WwwAuthenticate {
+    #[getset(get = "pub")]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Getters)]
+pub struct Challenge {
+    /// As sent by the server, compare with [`Challenge::is_scheme`]
     #[getset(get = "pub")]
     scheme: String,
     #[getset(get = "pub")]
-    pairs: HashMap<String, String>,
+    token68: Option<String>,
+    /// Parameter names are lowercased as they are case-insensitive
+    #[getset(get = "pub")]
+    params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    pub fn is_scheme(&self, scheme: &str) -> bool {
+        self.scheme.eq_ignore_ascii_case(scheme)
+    }
+
+    /// First value of the parameter `name`
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 }
 
 impl FromStr for WwwAuthenticate {
-    type Err = ();
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let tokens = LEXER.parse(s).map_err(|_| ())?;
-        PARSER.parse(tokens).map_err(|_| ())
+        let tokens = LEXER.parse(s).map_err(ParseError::from_errors)?;
+        let len = s.chars().count();
+        PARSER
+            .parse(Stream::from_iter(len..len, tokens.into_iter()))
+            .map_err(ParseError::from_errors)
     }
 }
 
-fn parser() -> impl Parser<Token, WwwAuthenticate, Error = Simple<Token>> {
-    // scheme <- key
-    let scheme = filter(|token| matches!(token, Token::Key(_))).map(|token| {
-        if let Token::Key(key) = token {
-            return key;
+/// Why a header could not be parsed, and where
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("malformed WWW-Authenticate header at character {offset}: {reason}")]
+pub struct ParseError {
+    offset: usize,
+    reason: String,
+}
+
+impl ParseError {
+    /// Character offset into the header value
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn from_errors<T: fmt::Display + std::hash::Hash + Eq>(errors: Vec<Simple<T>>) -> Self {
+        let error = errors
+            .into_iter()
+            .min_by_key(|e| e.span().start)
+            .expect("chumsky reports at least one error");
+        Self {
+            offset: error.span().start,
+            reason: error.to_string(),
         }
-        unreachable!()
-    });
-
-    // pair <- key eq value
-    let pair = filter(|token| matches!(token, Token::Key(_)))
-        .map(|token| {
-            if let Token::Key(key) = token {
-                return key;
-            }
-            unreachable!()
-        })
-        .then_ignore(just(Token::Eq))
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn parser() -> impl Parser<Token, WwwAuthenticate, Error = Simple<Token>> {
+    let word = select! { Token::Word(word) => word };
+    let quoted = select! { Token::Quoted(quoted) => quoted };
+    let eq = just(Token::Eq);
+    let comma = just(Token::Comma);
+
+    // auth-param <- token "=" ( token / quoted-string )
+    let param = word
+        .map(|key| key.to_lowercase())
+        .then_ignore(eq.clone())
+        .then(word.or(quoted));
+    // A comma followed by something other than a parameter starts the next challenge
+    let params = param
+        .clone()
+        .then(comma.clone().ignore_then(param).repeated())
+        .map(|(first, rest)| {
+            let mut params = vec![first];
+            params.extend(rest);
+            params
+        });
+
+    // token68 <- token *"=", alone up to the next challenge
+    let token68 = word
+        .then(eq.repeated())
+        .map(|(token, padding)| token + &"=".repeat(padding.len()))
+        .then_ignore(comma.clone().ignored().or(end()).rewind());
+
+    // challenge <- scheme [ token68 / params ]
+    let challenge = word
         .then(
-            filter(|token| matches!(token, Token::Value(_))).map(|token| {
-                if let Token::Value(value) = token {
-                    return value;
-                }
-                unreachable!()
-            }),
-        );
+            choice((
+                params.map(|params| (None, params)),
+                token68.map(|token68| (Some(token68), vec![])),
+            ))
+            .or_not(),
+        )
+        .map(|(scheme, rest)| {
+            let (token68, params) = rest.unwrap_or_default();
+            Challenge {
+                scheme,
+                token68,
+                params,
+            }
+        });
 
-    // s <- scheme ( pair )comma* $
-    scheme
-        .then(pair.separated_by(just(Token::Comma)))
-        .then_ignore(just(Token::End))
-        .map(|(scheme, pairs)| {
-            let pairs = pairs.into_iter().collect();
-            WwwAuthenticate { scheme, pairs }
-        })
+    // s <- challenge ( comma challenge )* $
+    challenge
+        .separated_by(comma)
+        .at_least(1)
+        .then_ignore(end())
+        .map(|challenges| WwwAuthenticate { challenges })
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, std::hash::Hash)]
 enum Token {
     Eq,
     Comma,
-    Key(String),
-    Value(String),
-    End,
+    /// A `token` or `token68` without its padding
+    Word(String),
+    /// A `quoted-string` with its quoted pairs unescaped
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Eq => write!(f, "="),
+            Token::Comma => write!(f, ","),
+            Token::Word(word) => write!(f, "{word}"),
+            Token::Quoted(quoted) => write!(f, "{quoted:?}"),
+        }
+    }
 }
 
-fn lexer() -> impl Parser<char, Vec<Token>, Error = Simple<char>> {
+fn lexer() -> impl Parser<char, Vec<Spanned<Token>>, Error = Simple<char>> {
     let eq = just('=').to(Token::Eq);
     let comma = just(',').to(Token::Comma);
-    let key = text::ident().map(Token::Key);
-    let value = none_of("\"")
+    // tchar, plus `/` for token68
+    let word = filter(|c: &char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~/".contains(*c))
         .repeated()
+        .at_least(1)
+        .collect::<String>()
+        .map(Token::Word);
+    // quoted-pair <- "\" ( HTAB / SP / VCHAR / obs-text )
+    let quoted_pair = just('\\').ignore_then(any());
+    let quoted = quoted_pair
+        .or(none_of("\"\\"))
+        .repeated()
+        .collect::<String>()
         .delimited_by(just('"'), just('"'))
-        .map(|chars| {
-            let string: String = chars.into_iter().collect();
-            Token::Value(string)
-        });
-    let end = end().to(Token::End);
-    let pad = choice((just(' ').ignored(),)).repeated();
-
-    let token = choice((eq, comma, key, value)).padded_by(pad);
-    token.repeated().then(end).map(|(mut tokens, end)| {
-        tokens.push(end);
-        tokens
-    })
+        .map(Token::Quoted);
+    let pad = one_of(" \t").repeated();
+
+    let token = choice((eq, comma, word, quoted))
+        .map_with_span(|token, span| (token, span))
+        .padded_by(pad.clone());
+    pad.ignore_then(token.repeated()).then_ignore(end())
 }
 
 #[cfg(test)]
@@ -102,32 +197,59 @@ mod tests {
     fn test_lexer() {
         let lexer = lexer();
         let src = r#"Bearer a="b", c="d" "#;
-        let tokens = lexer.parse(src).unwrap();
+        let tokens: Vec<_> = lexer
+            .parse(src)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect();
         assert_eq!(
             tokens,
             [
-                Token::Key("Bearer".into()),
-                Token::Key("a".into()),
+                Token::Word("Bearer".into()),
+                Token::Word("a".into()),
                 Token::Eq,
-                Token::Value("b".into()),
+                Token::Quoted("b".into()),
                 Token::Comma,
-                Token::Key("c".into()),
+                Token::Word("c".into()),
                 Token::Eq,
-                Token::Value("d".into()),
-                Token::End,
+                Token::Quoted("d".into()),
             ]
         );
     }
 
     #[test]
     fn test_parser() {
-        let parser = parser();
-        let lexer = lexer();
         let src = r#"Bearer a="b", c="d" "#;
-        let tokens = lexer.parse(src).unwrap();
-        let ast = parser.parse(tokens).unwrap();
-        assert_eq!(ast.scheme, "Bearer");
-        assert_eq!(ast.pairs.get("a").unwrap(), "b");
-        assert_eq!(ast.pairs.get("c").unwrap(), "d");
+        let ast: WwwAuthenticate = src.parse().unwrap();
+        let challenge = &ast.challenges()[0];
+        assert_eq!(challenge.scheme(), "Bearer");
+        assert_eq!(challenge.param("a"), Some("b"));
+        assert_eq!(challenge.param("c"), Some("d"));
+    }
+
+    #[test]
+    fn test_multiple_challenges() {
+        let src = r#"Newauth realm="apps", type=1, title="Login to \"apps\"", Basic REALM="simple", Negotiate YII/+a=="#;
+        let ast: WwwAuthenticate = src.parse().unwrap();
+        let [newauth, basic, negotiate] = ast.challenges().as_slice() else {
+            panic!("{ast:?}");
+        };
+        assert_eq!(newauth.scheme(), "Newauth");
+        assert_eq!(newauth.param("type"), Some("1"));
+        assert_eq!(newauth.param("title"), Some(r#"Login to "apps""#));
+        assert!(basic.is_scheme("basic"));
+        assert_eq!(basic.param("realm"), Some("simple"));
+        assert_eq!(negotiate.token68().as_deref(), Some("YII/+a=="));
+        assert!(negotiate.params().is_empty());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = r#"Bearer realm="a", ="b""#.parse::<WwwAuthenticate>().unwrap_err();
+        assert_eq!(err.offset(), 18);
+        let err = r#"Bearer realm="a"#.parse::<WwwAuthenticate>().unwrap_err();
+        assert_eq!(err.offset(), 15);
+        assert!("".parse::<WwwAuthenticate>().is_err());
     }
 }